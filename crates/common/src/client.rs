@@ -1,67 +1,493 @@
-use crate::config::ConnectionConfig;
-use bytes::Bytes;
-use serde::de::DeserializeOwned;
-use std::error::Error;
+use crate::{
+    config::ConnectionConfig,
+    credentials::{Authentication, Credentials},
+    endpoints::Endpoints,
+};
+use bytes::{Bytes, BytesMut};
+use flate2::{write::GzEncoder, Compression};
+use opentelemetry::trace::{SpanContext, TraceContextExt};
+use reqwest::{
+    header::{HeaderMap, CONTENT_ENCODING},
+    Certificate, Identity, NoProxy, Proxy, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_with::{serde_as, DisplayFromStr};
+use std::{
+    collections::hash_map::RandomState,
+    error::Error,
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Request bodies larger than this are compressed, if compression is enabled
+const COMPRESS_REQUEST_BODY_BYTES: usize = 64 * 1024;
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+
+const DEFAULT_USER_AGENT: &str = concat!("ndc-clickhouse/", env!("CARGO_PKG_VERSION"));
 
 pub fn get_http_client(
-    _connection_config: &ConnectionConfig,
+    connection_config: &ConnectionConfig,
 ) -> Result<reqwest::Client, reqwest::Error> {
-    // todo: we could make client come preconfigured with some headers such as for username and password?
-    let client = reqwest::Client::builder().build()?;
-    Ok(client)
+    let mut builder = reqwest::Client::builder()
+        // sets the Accept-Encoding header, and transparently decompresses responses.
+        // ClickHouse also supports lz4, but the client cannot decode it, so it is not requested
+        .gzip(connection_config.compression)
+        .zstd(connection_config.compression)
+        .user_agent(
+            connection_config
+                .user_agent
+                .as_deref()
+                .unwrap_or(DEFAULT_USER_AGENT),
+        );
+
+    if let Some(connect_timeout) = connection_config.connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    if let Some(request_timeout) = connection_config.request_timeout {
+        builder = builder.timeout(request_timeout);
+    }
+    if let Some(tcp_keepalive) = connection_config.tcp_keepalive {
+        builder = builder.tcp_keepalive(tcp_keepalive);
+    }
+    if let Some(pool_max_idle_per_host) = connection_config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(pool_max_idle_per_host);
+    }
+    if let Some(proxy) = &connection_config.proxy {
+        let no_proxy = connection_config
+            .no_proxy
+            .as_deref()
+            .and_then(NoProxy::from_string);
+        builder = builder.proxy(Proxy::all(proxy.clone())?.no_proxy(no_proxy));
+    }
+
+    let tls = &connection_config.tls;
+
+    if let Some(ca_certificates) = &tls.ca_certificates {
+        for certificate in Certificate::from_pem_bundle(ca_certificates)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(client_identity) = &tls.client_identity {
+        builder = builder.identity(Identity::from_pem(client_identity)?);
+    }
+    if tls.insecure_skip_verify {
+        tracing::warn!("TLS certificate verification is disabled. Do not use this in production");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder.build()
 }
 
+/// Execute a statement, retrying connection errors and transient failures with jittered exponential backoff.
+/// Each attempt is sent to an endpoint selected by the load balancing strategy. Endpoints that cannot be reached
+/// are ejected, so retries fail over to the remaining endpoints.
+/// Only read queries are executed through this module, so retrying is safe.
+/// Mutations must not be retried this way, as a failed attempt may still have been applied
 async fn execute_query(
     client: &reqwest::Client,
     connection_config: &ConnectionConfig,
+    endpoints: &Endpoints,
     statement: &str,
     parameters: &Vec<(String, String)>,
-) -> Result<reqwest::Response, reqwest::Error> {
-    client
-        .post(&connection_config.url)
-        .header("X-ClickHouse-User", &connection_config.username)
-        .header("X-ClickHouse-Key", &connection_config.password)
-        .query(parameters)
-        .body(statement.to_owned())
-        .send()
+) -> Result<reqwest::Response, ClickHouseError> {
+    let compress_body =
+        connection_config.compression && statement.len() > COMPRESS_REQUEST_BODY_BYTES;
+
+    let body = if compress_body {
+        Bytes::from(gzip(statement).map_err(ClickHouseError::Compression)?)
+    } else {
+        Bytes::copy_from_slice(statement.as_bytes())
+    };
+
+    let mut attempt = 1;
+    let mut credentials_reloaded = false;
+    let mut retrying = false;
+
+    loop {
+        let endpoint = endpoints.select();
+
+        let result = send_request(
+            client,
+            connection_config,
+            endpoint.url(),
+            body.clone(),
+            compress_body,
+            retrying,
+            parameters,
+        )
         .instrument(tracing::info_span!(
             "Execute HTTP request",
+            attempt,
+            url = endpoint.url(),
             internal.visibility = "user"
         ))
-        .await
+        .await;
+
+        if result.as_ref().is_err_and(is_endpoint_failure) {
+            endpoint.eject();
+        }
+        drop(endpoint);
+        retrying = true;
+
+        if let Err(err) = &result {
+            if retry_with_reloaded_credentials(
+                err,
+                &connection_config.credentials,
+                &mut credentials_reloaded,
+            )
+            .await
+            {
+                continue;
+            }
+        }
+
+        match result {
+            Err(err) if attempt < connection_config.max_attempts && is_retryable(&err) => {
+                let delay = retry_delay(attempt);
+                tracing::warn!(
+                    attempt,
+                    delay_ms = delay.as_millis() as u64,
+                    "Retrying ClickHouse request: {err}"
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
 }
 
+async fn send_request(
+    client: &reqwest::Client,
+    connection_config: &ConnectionConfig,
+    url: &str,
+    body: Bytes,
+    compressed_body: bool,
+    replace_running_query: bool,
+    parameters: &Vec<(String, String)>,
+) -> Result<reqwest::Response, ClickHouseError> {
+    let mut request = authenticate(client.post(url), &connection_config.credentials);
+
+    // ClickHouse continues the trace, so query spans can be found in system.opentelemetry_span_log
+    if let Some(span_context) = current_span_context() {
+        request = request.header(
+            "traceparent",
+            format!(
+                "00-{}-{}-{:02x}",
+                span_context.trace_id(),
+                span_context.span_id(),
+                span_context.trace_flags().to_u8()
+            ),
+        );
+
+        let trace_state = span_context.trace_state().header();
+        if !trace_state.is_empty() {
+            request = request.header("tracestate", trace_state);
+        }
+    }
+
+    request = request.query(parameters);
+
+    if connection_config.compression {
+        // ClickHouse only compresses responses if asked to
+        request = request.query(&[("enable_http_compression", "1")]);
+    }
+
+    if replace_running_query {
+        // retries reuse the query id, so the query can be killed using a single id.
+        // A previous attempt may still be running, and would otherwise cause QUERY_WITH_SAME_ID_IS_ALREADY_RUNNING
+        request = request.query(&[("replace_running_query", "1")]);
+    }
+
+    if compressed_body {
+        request = request.header(CONTENT_ENCODING, "gzip");
+    }
+
+    let response = request.body(body).send().await?;
+
+    error_for_exception(response).await
+}
+
+fn authenticate(
+    request: reqwest::RequestBuilder,
+    credentials: &Credentials,
+) -> reqwest::RequestBuilder {
+    match credentials.authentication() {
+        Authentication::Basic { username, password } => request
+            .header("X-ClickHouse-User", username)
+            .header("X-ClickHouse-Key", password),
+        Authentication::Bearer { token } => request.bearer_auth(token),
+    }
+}
+
+/// ClickHouse rejects invalid credentials with an exception, proxies may respond with 401 instead
+fn is_authentication_failure(error: &ClickHouseError) -> bool {
+    match error {
+        // AUTHENTICATION_FAILED
+        ClickHouseError::Exception { code, .. } => *code == 516,
+        ClickHouseError::Request(err) => err.status() == Some(StatusCode::UNAUTHORIZED),
+        ClickHouseError::ResponseTooLarge { .. } | ClickHouseError::Compression(_) => false,
+    }
+}
+
+/// Credential files may have been rotated since we last read them.
+/// On the first authentication failure of a request, re-read them without waiting for the next periodic reload.
+/// Returns whether the request should be retried with the new credentials
+async fn retry_with_reloaded_credentials(
+    error: &ClickHouseError,
+    credentials: &Credentials,
+    credentials_reloaded: &mut bool,
+) -> bool {
+    if *credentials_reloaded || !is_authentication_failure(error) {
+        return false;
+    }
+
+    *credentials_reloaded = true;
+
+    reload_credentials(credentials).await
+}
+
+/// Re-read credentials from files. Returns whether the credentials changed
+async fn reload_credentials(credentials: &Credentials) -> bool {
+    if !credentials.is_reloadable() {
+        return false;
+    }
+
+    match credentials.reload().await {
+        Ok(changed) => {
+            if changed {
+                tracing::info!(
+                    "Reloaded rotated ClickHouse credentials after authentication failure"
+                );
+            }
+            changed
+        }
+        Err(err) => {
+            tracing::warn!("Failed to reload ClickHouse credentials: {err}");
+            false
+        }
+    }
+}
+
+/// Connection errors, gateway errors and exceptions caused by transient conditions on the server may succeed if retried.
+/// Timeouts are not retried: the query may still be running, and a retry would only add load to a struggling server
+fn is_retryable(error: &ClickHouseError) -> bool {
+    match error {
+        // UNEXPECTED_END_OF_FILE, TOO_MANY_SIMULTANEOUS_QUERIES, SOCKET_TIMEOUT, NETWORK_ERROR, ALL_CONNECTION_TRIES_FAILED
+        ClickHouseError::Exception { code, .. } => matches!(code, 3 | 202 | 209 | 210 | 279),
+        ClickHouseError::Request(err) if err.is_timeout() => false,
+        ClickHouseError::Request(err) => {
+            err.is_connect()
+                || matches!(
+                    err.status(),
+                    Some(
+                        StatusCode::BAD_GATEWAY
+                            | StatusCode::SERVICE_UNAVAILABLE
+                            | StatusCode::GATEWAY_TIMEOUT
+                    )
+                )
+        }
+        ClickHouseError::ResponseTooLarge { .. } | ClickHouseError::Compression(_) => false,
+    }
+}
+
+/// Failures that indicate the endpoint itself is unreachable or unhealthy, rather than a problem with the query
+fn is_endpoint_failure(error: &ClickHouseError) -> bool {
+    match error {
+        ClickHouseError::Request(err) => {
+            err.is_connect()
+                || matches!(
+                    err.status(),
+                    Some(
+                        StatusCode::BAD_GATEWAY
+                            | StatusCode::SERVICE_UNAVAILABLE
+                            | StatusCode::GATEWAY_TIMEOUT
+                    )
+                )
+        }
+        ClickHouseError::Exception { .. }
+        | ClickHouseError::ResponseTooLarge { .. }
+        | ClickHouseError::Compression(_) => false,
+    }
+}
+
+/// Exponential backoff with full jitter: a random delay between zero and a maximum that doubles with each attempt.
+/// Attempts are counted from 1, an attempt of 0 is treated as the first attempt
+fn retry_delay(attempt: u32) -> Duration {
+    let max_delay = RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(RETRY_MAX_DELAY);
+
+    // the std hasher is randomly seeded, which is good enough for jitter
+    let random = RandomState::new().build_hasher().finish();
+
+    max_delay.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClickHouseError {
+    /// ClickHouse failed to execute the query, and returned an exception
+    #[error("ClickHouse exception {code}: {message}")]
+    Exception {
+        /// The ClickHouse error code, from the `X-ClickHouse-Exception-Code` header
+        code: u32,
+        message: String,
+        query_id: Option<String>,
+    },
+    /// The response exceeded the configured maximum size, and was not read further
+    #[error("Query response exceeded the maximum size of {max_response_bytes} bytes")]
+    ResponseTooLarge { max_response_bytes: u64 },
+    /// The request body could not be compressed
+    #[error("Failed to compress request body: {0}")]
+    Compression(io::Error),
+    /// The request could not be sent, or the response could not be read
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+}
+
+impl ClickHouseError {
+    /// The ClickHouse error code, if ClickHouse returned an exception
+    pub fn code(&self) -> Option<u32> {
+        match self {
+            ClickHouseError::Exception { code, .. } => Some(*code),
+            ClickHouseError::ResponseTooLarge { .. }
+            | ClickHouseError::Compression(_)
+            | ClickHouseError::Request(_) => None,
+        }
+    }
+}
+
+/// Turn an error response into a `ClickHouseError`, parsing the exception code and message if available
+async fn error_for_exception(
+    response: reqwest::Response,
+) -> Result<reqwest::Response, ClickHouseError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned)
+    };
+
+    let code = header("X-ClickHouse-Exception-Code").and_then(|code| code.parse().ok());
+    let query_id = header("X-ClickHouse-Query-Id");
+
+    match code {
+        Some(code) => {
+            let message = response.text().await?.trim().to_owned();
+
+            Err(ClickHouseError::Exception {
+                code,
+                message,
+                query_id,
+            })
+        }
+        None => Ok(response.error_for_status()?),
+    }
+}
+
+/// Execution statistics sent by ClickHouse in the `X-ClickHouse-Summary` header.
+/// The header is sent along with the response headers, so statistics may be incomplete
+/// if ClickHouse starts sending the response before the query completes
+#[serde_as]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct QuerySummary {
+    #[serde_as(as = "DisplayFromStr")]
+    pub read_rows: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    pub read_bytes: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    pub result_rows: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    pub result_bytes: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    pub elapsed_ns: u64,
+}
+
+impl QuerySummary {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = headers.get("X-ClickHouse-Summary")?;
+
+        match serde_json::from_slice(header.as_bytes()) {
+            Ok(summary) => Some(summary),
+            Err(err) => {
+                tracing::warn!("Failed to parse X-ClickHouse-Summary header: {err}");
+                None
+            }
+        }
+    }
+}
+
+/// Execute a query, reading the full response body into memory.
+/// The body is read chunk by chunk as it is streamed from ClickHouse, but is not streamed any further:
+/// query responses are returned as a single serialized body, so the connector must hold the whole response.
+/// If the response exceeds the maximum size, we stop reading and return an error, dropping the connection.
+/// Callers should cancel the query in that case, as ClickHouse may keep running it
 pub async fn execute_bytes_query(
     client: &reqwest::Client,
     connection_config: &ConnectionConfig,
+    endpoints: &Endpoints,
     statement: &str,
     parameters: &Vec<(String, String)>,
-) -> Result<Bytes, reqwest::Error> {
-    let response = execute_query(client, connection_config, statement, parameters).await?;
+    max_response_bytes: Option<u64>,
+) -> Result<(Bytes, Option<QuerySummary>), ClickHouseError> {
+    let response =
+        execute_query(client, connection_config, endpoints, statement, parameters).await?;
 
-    let response = response
-        .error_for_status()?
-        .bytes()
+    let summary = QuerySummary::from_headers(response.headers());
+
+    let body = read_response_body(response, max_response_bytes)
         .instrument(tracing::info_span!(
             "Read HTTP response",
             internal.visibility = "user"
         ))
         .await?;
 
-    Ok(response)
+    Ok((body, summary))
+}
+
+/// Buffer the response body, which caps the memory used per query when a maximum size is set
+async fn read_response_body(
+    mut response: reqwest::Response,
+    max_response_bytes: Option<u64>,
+) -> Result<Bytes, ClickHouseError> {
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = response.chunk().await? {
+        if let Some(max_response_bytes) = max_response_bytes {
+            if (body.len() + chunk.len()) as u64 > max_response_bytes {
+                return Err(ClickHouseError::ResponseTooLarge { max_response_bytes });
+            }
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body.freeze())
 }
 
 pub async fn execute_text_query<T: DeserializeOwned>(
     client: &reqwest::Client,
     connection_config: &ConnectionConfig,
+    endpoints: &Endpoints,
     statement: &str,
     parameters: &Vec<(String, String)>,
-) -> Result<String, reqwest::Error> {
-    let response = execute_query(client, connection_config, statement, parameters).await?;
+) -> Result<String, ClickHouseError> {
+    let response =
+        execute_query(client, connection_config, endpoints, statement, parameters).await?;
 
     let response = response
-        .error_for_status()?
         .text()
         .instrument(tracing::info_span!("Parse HTTP response"))
         .await?;
@@ -72,13 +498,14 @@ pub async fn execute_text_query<T: DeserializeOwned>(
 pub async fn execute_json_query<T: DeserializeOwned>(
     client: &reqwest::Client,
     connection_config: &ConnectionConfig,
+    endpoints: &Endpoints,
     statement: &str,
     parameters: &Vec<(String, String)>,
-) -> Result<T, reqwest::Error> {
-    let response = execute_query(client, connection_config, statement, parameters).await?;
+) -> Result<T, ClickHouseError> {
+    let response =
+        execute_query(client, connection_config, endpoints, statement, parameters).await?;
 
     let response: T = response
-        .error_for_status()?
         .json()
         .instrument(tracing::info_span!("Parse HTTP response"))
         .await?;
@@ -86,24 +513,620 @@ pub async fn execute_json_query<T: DeserializeOwned>(
     Ok(response)
 }
 
+fn gzip(body: &str) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(body.as_bytes())?;
+    encoder.finish()
+}
+
+/// The OpenTelemetry span context of the current span, if it is being traced
+fn current_span_context() -> Option<SpanContext> {
+    let context = tracing::Span::current().context();
+    let span_context = context.span().span_context().clone();
+
+    span_context.is_valid().then_some(span_context)
+}
+
+/// Generate an id for a query. ClickHouse uses the id to identify the query in `system.processes` and `system.query_log`
+/// If the current span is being traced, the id is made of the trace and span ids, so queries can be matched to traces.
+/// Otherwise, the id is unique to this process
+pub fn generate_query_id() -> String {
+    if let Some(span_context) = current_span_context() {
+        return format!("{}-{}", span_context.trace_id(), span_context.span_id());
+    }
+
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+    format!(
+        "ndc-clickhouse-{:x}-{timestamp:x}-{sequence:x}",
+        std::process::id()
+    )
+}
+
+/// Cancel a running query. Does not wait for the query to be stopped.
+/// The query id must have been generated by `generate_query_id`, as it is inlined into the statement.
+/// We don't know which endpoint is running the query, so the statement is sent to all of them
+pub async fn kill_query(
+    client: &reqwest::Client,
+    connection_config: &ConnectionConfig,
+    endpoints: &Endpoints,
+    query_id: &str,
+) -> Result<(), ClickHouseError> {
+    let statement = Bytes::from(format!("KILL QUERY WHERE query_id = '{query_id}' ASYNC"));
+
+    let mut result = Ok(());
+
+    // try every endpoint even if some fail, reporting the last failure
+    for url in endpoints.urls() {
+        if let Err(err) = send_request(
+            client,
+            connection_config,
+            url,
+            statement.clone(),
+            false,
+            false,
+            &vec![],
+        )
+        .await
+        {
+            result = Err(err);
+        }
+    }
+
+    result
+}
+
 pub async fn ping(
     client: &reqwest::Client,
     connection_config: &ConnectionConfig,
+    url: &str,
 ) -> Result<(), Box<dyn Error>> {
-    let last_char = connection_config.url.chars().last();
-
-    let url = if let Some('/') = last_char {
-        format!("{}ping", connection_config.url)
+    let url = if url.ends_with('/') {
+        format!("{url}ping")
     } else {
-        format!("{}/ping", connection_config.url)
+        format!("{url}/ping")
     };
 
-    let _request = client
-        .get(&url)
-        .header("X-ClickHouse-User", &connection_config.username)
-        .header("X-ClickHouse-Key", &connection_config.password)
+    let _request = authenticate(client.get(&url), &connection_config.credentials)
         .send()
         .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::Secret;
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Read},
+        net::{TcpListener, TcpStream},
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    #[derive(Debug, Clone)]
+    struct MockRequest {
+        /// the request line and headers
+        head: String,
+        body: Vec<u8>,
+    }
+
+    impl MockRequest {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.head.lines().find_map(|line| {
+                let (header_name, value) = line.split_once(':')?;
+                header_name
+                    .eq_ignore_ascii_case(name)
+                    .then_some(value.trim())
+            })
+        }
+    }
+
+    /// A minimal HTTP server, answering every request with the response built by `respond`.
+    /// Requests are recorded, so tests can check what the client sent
+    struct MockServer {
+        url: String,
+        requests: Arc<Mutex<Vec<MockRequest>>>,
+    }
+
+    impl MockServer {
+        fn start(respond: impl Fn(&MockRequest) -> Vec<u8> + Send + 'static) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").expect("Should bind mock server");
+            let url = format!(
+                "http://{}/",
+                listener.local_addr().expect("Should have local address")
+            );
+            let requests = Arc::new(Mutex::new(vec![]));
+            let recorded_requests = requests.clone();
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(mut stream) = stream else { continue };
+                    let Some(request) = read_request(&mut stream) else {
+                        continue;
+                    };
+
+                    recorded_requests
+                        .lock()
+                        .expect("Should lock requests")
+                        .push(request.clone());
+
+                    // the client may have given up waiting, in which case writing fails
+                    let _ = stream.write_all(&respond(&request));
+                }
+            });
+
+            Self { url, requests }
+        }
+        fn requests(&self) -> Vec<MockRequest> {
+            self.requests.lock().expect("Should lock requests").clone()
+        }
+    }
+
+    fn read_request(stream: &mut TcpStream) -> Option<MockRequest> {
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line);
+        }
+
+        let mut request = MockRequest { head, body: vec![] };
+        let content_length = request
+            .header("Content-Length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0);
+        request.body = vec![0; content_length];
+        reader.read_exact(&mut request.body).ok()?;
+
+        Some(request)
+    }
+
+    fn response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            body.len()
+        );
+        for (name, value) in headers {
+            response.push_str(&format!("{name}: {value}\r\n"));
+        }
+        response.push_str("\r\n");
+
+        let mut response = response.into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    /// The error returned when requesting the url, as it would be returned when executing a query
+    async fn request_error(client: &reqwest::Client, url: &str) -> ClickHouseError {
+        let result = match client.post(url).send().await {
+            Ok(response) => error_for_exception(response).await,
+            Err(err) => Err(err.into()),
+        };
+
+        result.expect_err("Request should fail")
+    }
+
+    fn exception(code: u32) -> ClickHouseError {
+        ClickHouseError::Exception {
+            code,
+            message: format!("Code: {code}. DB::Exception"),
+            query_id: None,
+        }
+    }
+
+    fn authentication_failure() -> ClickHouseError {
+        ClickHouseError::Exception {
+            code: 516,
+            message: "Authentication failed".to_owned(),
+            query_id: None,
+        }
+    }
+
+    fn secret_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ndc-clickhouse-{name}-{}", std::process::id()));
+        fs::write(&path, content).expect("Should write secret file");
+        path
+    }
+
+    fn headers(credentials: &Credentials) -> reqwest::header::HeaderMap {
+        authenticate(
+            reqwest::Client::new().post("http://localhost:8123"),
+            credentials,
+        )
+        .build()
+        .expect("Should build request")
+        .headers()
+        .to_owned()
+    }
+
+    #[test]
+    fn authenticate_with_username_and_password() {
+        let credentials = Credentials::basic(
+            Secret::Value("user".to_owned()),
+            Secret::Value("password".to_owned()),
+        )
+        .expect("Should read credentials");
+
+        let headers = headers(&credentials);
+
+        assert_eq!(headers["X-ClickHouse-User"], "user");
+        assert_eq!(headers["X-ClickHouse-Key"], "password");
+        assert!(headers.get(reqwest::header::AUTHORIZATION).is_none());
+    }
+    #[test]
+    fn authenticate_with_bearer_token() {
+        let credentials =
+            Credentials::bearer(Secret::Value("token".to_owned())).expect("Should read token");
+
+        let headers = headers(&credentials);
+
+        assert_eq!(headers[reqwest::header::AUTHORIZATION], "Bearer token");
+        assert!(headers.get("X-ClickHouse-User").is_none());
+        assert!(headers.get("X-ClickHouse-Key").is_none());
+    }
+    #[tokio::test]
+    async fn reload_credentials_once_per_request() {
+        let path = secret_file("token", "first");
+        let credentials =
+            Credentials::bearer(Secret::File(path.to_owned())).expect("Should read token");
+        let mut credentials_reloaded = false;
+
+        fs::write(&path, "second").expect("Should rotate token");
+        assert!(
+            retry_with_reloaded_credentials(
+                &authentication_failure(),
+                &credentials,
+                &mut credentials_reloaded
+            )
+            .await,
+            "rotated credentials should be retried"
+        );
+        assert_eq!(
+            credentials.authentication(),
+            Authentication::Bearer {
+                token: "second".to_owned()
+            }
+        );
+
+        fs::write(&path, "third").expect("Should rotate token");
+        assert!(
+            !retry_with_reloaded_credentials(
+                &authentication_failure(),
+                &credentials,
+                &mut credentials_reloaded
+            )
+            .await,
+            "credentials should only be reloaded once per request"
+        );
+        assert_eq!(
+            credentials.authentication(),
+            Authentication::Bearer {
+                token: "second".to_owned()
+            }
+        );
+
+        fs::remove_file(&path).expect("Should remove token file");
+    }
+    #[tokio::test]
+    async fn do_not_retry_unchanged_credentials() {
+        let path = secret_file("password", "password");
+        let credentials = Credentials::basic(
+            Secret::Value("user".to_owned()),
+            Secret::File(path.to_owned()),
+        )
+        .expect("Should read credentials");
+
+        assert!(
+            !retry_with_reloaded_credentials(&authentication_failure(), &credentials, &mut false)
+                .await,
+            "unchanged credentials should not be retried"
+        );
+
+        fs::remove_file(&path).expect("Should remove password file");
+    }
+    #[tokio::test]
+    async fn do_not_reload_credentials_set_directly() {
+        let credentials =
+            Credentials::bearer(Secret::Value("token".to_owned())).expect("Should read token");
+        let mut credentials_reloaded = false;
+
+        assert!(
+            !retry_with_reloaded_credentials(
+                &authentication_failure(),
+                &credentials,
+                &mut credentials_reloaded
+            )
+            .await
+        );
+    }
+    #[test]
+    fn retry_transient_exceptions() {
+        for (code, retryable) in [
+            (3, true),
+            (202, true),
+            (209, true),
+            (210, true),
+            (279, true),
+            (60, false),
+            (62, false),
+            (516, false),
+        ] {
+            assert_eq!(
+                is_retryable(&exception(code)),
+                retryable,
+                "exception {code}"
+            );
+            assert!(!is_endpoint_failure(&exception(code)), "exception {code}");
+        }
+
+        let too_large = ClickHouseError::ResponseTooLarge {
+            max_response_bytes: 1,
+        };
+        let compression = ClickHouseError::Compression(io::Error::other("failed"));
+
+        assert!(!is_retryable(&too_large));
+        assert!(!is_endpoint_failure(&too_large));
+        assert!(!is_retryable(&compression));
+        assert!(!is_endpoint_failure(&compression));
+    }
+    #[tokio::test]
+    async fn retry_gateway_errors() {
+        for (status, retryable) in [
+            ("502 Bad Gateway", true),
+            ("503 Service Unavailable", true),
+            ("504 Gateway Timeout", true),
+            ("500 Internal Server Error", false),
+            ("400 Bad Request", false),
+            ("401 Unauthorized", false),
+        ] {
+            let server = MockServer::start(move |_| response(status, &[], b""));
+
+            let error = request_error(&reqwest::Client::new(), &server.url).await;
+
+            assert!(matches!(error, ClickHouseError::Request(_)), "{status}");
+            assert_eq!(is_retryable(&error), retryable, "{status}");
+            assert_eq!(is_endpoint_failure(&error), retryable, "{status}");
+        }
+    }
+    #[tokio::test]
+    async fn retry_connection_failures() {
+        // bind a port, then close it, so nothing is listening
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Should bind listener");
+        let url = format!(
+            "http://{}/",
+            listener.local_addr().expect("Should have local address")
+        );
+        drop(listener);
+
+        let error = request_error(&reqwest::Client::new(), &url).await;
+
+        assert!(matches!(&error, ClickHouseError::Request(err) if err.is_connect()));
+        assert!(is_retryable(&error));
+        assert!(is_endpoint_failure(&error));
+    }
+    #[tokio::test]
+    async fn do_not_retry_timeouts() {
+        let server = MockServer::start(|_| {
+            thread::sleep(Duration::from_millis(500));
+            response("200 OK", &[], b"")
+        });
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .expect("Should build client");
+
+        let error = request_error(&client, &server.url).await;
+
+        assert!(matches!(&error, ClickHouseError::Request(err) if err.is_timeout()));
+        assert!(!is_retryable(&error));
+        assert!(!is_endpoint_failure(&error));
+    }
+    #[test]
+    fn retry_delay_grows_exponentially_up_to_maximum() {
+        for attempt in 1..=40 {
+            let max_delay = RETRY_BASE_DELAY
+                .saturating_mul(2u32.saturating_pow(attempt - 1))
+                .min(RETRY_MAX_DELAY);
+
+            for _ in 0..20 {
+                assert!(retry_delay(attempt) <= max_delay, "attempt {attempt}");
+            }
+        }
+
+        assert!((0..20).any(|_| retry_delay(40) > RETRY_BASE_DELAY));
+    }
+    #[test]
+    fn retry_delay_treats_attempt_zero_as_first_attempt() {
+        for _ in 0..20 {
+            assert!(retry_delay(0) <= RETRY_BASE_DELAY);
+        }
+    }
+    #[test]
+    fn parse_query_summary() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-ClickHouse-Summary",
+            r#"{"read_rows":"150","read_bytes":"2400","written_rows":"0","written_bytes":"0","total_rows_to_read":"150","result_rows":"10","result_bytes":"320","elapsed_ns":"1234567"}"#
+                .parse()
+                .expect("Should parse header value"),
+        );
+
+        let summary = QuerySummary::from_headers(&headers).expect("Should parse summary");
+
+        assert_eq!(summary.read_rows, 150);
+        assert_eq!(summary.read_bytes, 2400);
+        assert_eq!(summary.result_rows, 10);
+        assert_eq!(summary.result_bytes, 320);
+        assert_eq!(summary.elapsed_ns, 1234567);
+    }
+    #[test]
+    fn default_missing_query_summary_fields() {
+        let mut headers = HeaderMap::new();
+        // older ClickHouse versions do not report result or elapsed statistics
+        headers.insert(
+            "X-ClickHouse-Summary",
+            r#"{"read_rows":"150","read_bytes":"2400"}"#
+                .parse()
+                .expect("Should parse header value"),
+        );
+
+        let summary = QuerySummary::from_headers(&headers).expect("Should parse summary");
+
+        assert_eq!(summary.read_rows, 150);
+        assert_eq!(summary.result_rows, 0);
+        assert_eq!(summary.elapsed_ns, 0);
+    }
+    #[test]
+    fn ignore_missing_or_malformed_query_summary() {
+        assert!(QuerySummary::from_headers(&HeaderMap::new()).is_none());
+
+        for summary in [
+            r#"{"read_rows":"150""#,
+            r#"{"read_rows":"many"}"#,
+            r#""150""#,
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "X-ClickHouse-Summary",
+                summary.parse().expect("Should parse header value"),
+            );
+
+            assert!(QuerySummary::from_headers(&headers).is_none(), "{summary}");
+        }
+    }
+    #[test]
+    fn derive_query_id_from_trace() {
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        // the tracer only samples spans while the provider is alive
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("Execute SQL query");
+            let span_context = span.context().span().span_context().clone();
+            assert!(span_context.is_valid());
+
+            let query_id = span.in_scope(generate_query_id);
+
+            assert_eq!(
+                query_id,
+                format!("{}-{}", span_context.trace_id(), span_context.span_id())
+            );
+        });
+    }
+    #[test]
+    fn generate_unique_query_ids_without_trace() {
+        let first = generate_query_id();
+        let second = generate_query_id();
+
+        let prefix = format!("ndc-clickhouse-{:x}-", std::process::id());
+
+        for query_id in [&first, &second] {
+            let suffix = query_id
+                .strip_prefix(&prefix)
+                .expect("Query id should start with the process id");
+            let parts: Vec<_> = suffix.split('-').collect();
+
+            // timestamp and sequence number
+            assert_eq!(parts.len(), 2, "{query_id}");
+            assert!(parts
+                .iter()
+                .all(|part| u128::from_str_radix(part, 16).is_ok()));
+        }
+
+        assert_ne!(first, second);
+    }
+    fn compressed_connection(url: &str) -> ConnectionConfig {
+        let env = crate::config::ConfigurationEnvironment::from_simulated_environment(
+            [
+                ("CLICKHOUSE_URL", url),
+                ("CLICKHOUSE_USERNAME", "default"),
+                ("CLICKHOUSE_PASSWORD", ""),
+                ("CLICKHOUSE_COMPRESSION", "true"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect(),
+        );
+
+        crate::config::get_connection_configuration(&env)
+            .expect("Should read connection configuration")
+    }
+
+    #[tokio::test]
+    async fn compress_requests_and_decompress_responses() {
+        let server = MockServer::start(|request| {
+            let body = if request
+                .header("Accept-Encoding")
+                .is_some_and(|encoding| encoding.contains("gzip"))
+            {
+                gzip(r#"[{"rows":[]}]"#).expect("Should compress response")
+            } else {
+                b"uncompressed".to_vec()
+            };
+            response("200 OK", &[("Content-Encoding", "gzip")], &body)
+        });
+        let connection = compressed_connection(&server.url);
+        let client = get_http_client(&connection).expect("Should build client");
+        let endpoints = Endpoints::new(&connection);
+        // large enough to be compressed
+        let statement = format!("SELECT '{}'", "a".repeat(COMPRESS_REQUEST_BODY_BYTES));
+
+        let (body, _) =
+            execute_bytes_query(&client, &connection, &endpoints, &statement, &vec![], None)
+                .await
+                .expect("Query should succeed");
+
+        assert_eq!(body, r#"[{"rows":[]}]"#);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+
+        assert!(request.head.contains("enable_http_compression=1"));
+        assert_eq!(request.header("Content-Encoding"), Some("gzip"));
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(request.body.as_slice())
+            .read_to_string(&mut decoded)
+            .expect("Request body should be gzip compressed");
+        assert_eq!(decoded, statement);
+    }
+    #[tokio::test]
+    async fn do_not_compress_small_requests() {
+        let server = MockServer::start(|_| response("200 OK", &[], b"[]"));
+        let connection = compressed_connection(&server.url);
+        let client = get_http_client(&connection).expect("Should build client");
+        let endpoints = Endpoints::new(&connection);
+
+        execute_bytes_query(&client, &connection, &endpoints, "SELECT 1", &vec![], None)
+            .await
+            .expect("Query should succeed");
+
+        let request = &server.requests()[0];
+        assert!(request.header("Content-Encoding").is_none());
+        assert_eq!(request.body, b"SELECT 1");
+    }
+}
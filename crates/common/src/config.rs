@@ -1,20 +1,35 @@
 use crate::{
-    clickhouse_parser::{datatype::ClickHouseDataType, parameterized_query::ParameterizedQuery},
+    clickhouse_parser::{
+        datatype::ClickHouseDataType,
+        parameterized_query::{
+            Parameter, ParameterType, ParameterizedQuery, ParameterizedQueryElement,
+        },
+    },
     config_file::{
-        MaybeClickhouseDataType, ParameterizedQueryConfigFile, ParameterizedQueryExposedAs,
-        PrimaryKey, ReturnType, ServerConfigFile, TableConfigFile, CONFIG_FILE_NAME,
+        DatabaseRoutingConfig, HttpClientConfig, MaybeClickhouseDataType,
+        ParameterizedQueryConfigFile, ParameterizedQueryExposedAs, PrewhereConfig, PrimaryKey,
+        ReturnType, RoleForwardingConfig, ScalarTypesConfig, ServerConfigFile, TableConfigFile,
+        TenancyConfigFile, CONFIG_FILE_NAME,
     },
+    credentials::{Credentials, Secret},
     format::display_period_separated,
+    schema::{validate_type_names, TypeNameCollision},
 };
-use ndc_models::{ArgumentName, CollectionName, FieldName, ObjectTypeName};
+use ndc_models::{self as models, ArgumentName, CollectionName, FieldName, ObjectTypeName};
 use std::{
-    collections::{BTreeMap, HashMap},
-    env, io,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env, fmt, io, iter,
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 use tokio::fs;
 
+/// Separates the parts of generated type names, eg. `<table alias>.<column alias>` for a tuple column.
+/// Hardcoding separator for now, to avoid prematurely exposing configuration options we may not want to keep
+/// if we make this configurable, we must default to this separator when the option is not provided
+pub const NAMESPACE_SEPARATOR: &str = ".";
+
 #[derive(Debug, Clone)]
 /// In memory, runtime configuration, built from the configuration file(s) and environment variables
 pub struct ServerConfig {
@@ -24,6 +39,106 @@ pub struct ServerConfig {
     pub table_types: BTreeMap<ObjectTypeName, TableType>,
     pub tables: BTreeMap<CollectionName, TableConfig>,
     pub queries: BTreeMap<CollectionName, ParameterizedQueryConfig>,
+    /// the session timezone, if configured. Otherwise the server default is used
+    pub timezone: Option<String>,
+    pub scalar_types: ScalarTypesConfig,
+    /// ClickHouse settings applied to every query
+    pub settings: BTreeMap<String, String>,
+    /// the maximum size of a query response, if configured
+    pub max_response_bytes: Option<u64>,
+    pub role_forwarding: Option<RoleForwardingConfig>,
+    pub tenancy: Option<TenancyConfig>,
+    pub database_routing: Option<DatabaseRoutingConfig>,
+}
+
+impl ServerConfig {
+    /// Arguments accepted by every table and query, in addition to their own arguments.
+    /// These supply values for the whole request, and are not passed to views or parameterized queries.
+    /// None of these arguments are nullable: the role is always required,
+    /// and the tenant and database are required whenever a tenant or routed table is referenced
+    pub fn request_arguments(&self) -> Vec<(ArgumentName, ClickHouseDataType)> {
+        let role_argument = self.role_forwarding.iter().map(|role_forwarding| {
+            (
+                role_forwarding.argument.to_owned(),
+                ClickHouseDataType::String,
+            )
+        });
+        let tenant_argument = self
+            .tenancy
+            .iter()
+            .map(|tenancy| (tenancy.argument.to_owned(), tenancy.data_type.to_owned()));
+
+        let database_argument = self.database_routing.iter().map(|database_routing| {
+            (
+                database_routing.argument.to_owned(),
+                ClickHouseDataType::String,
+            )
+        });
+
+        role_argument
+            .chain(tenant_argument)
+            .chain(database_argument)
+            .collect()
+    }
+    /// The tenant column of a table, if the table is shared by multiple tenants
+    pub fn tenant_column(&self, table_alias: &CollectionName) -> Option<&FieldName> {
+        self.tenancy
+            .as_ref()
+            .and_then(|tenancy| tenancy.columns.get(table_alias))
+    }
+    pub fn is_request_argument(&self, name: &ArgumentName) -> bool {
+        self.request_arguments()
+            .iter()
+            .any(|(argument_name, _)| argument_name == name)
+    }
+    /// ClickHouse settings sent along with a query, as url parameters.
+    /// Top level settings are overridden by settings for collections in relationships,
+    /// which are in turn overridden by settings for the root collection of the request.
+    /// Collections in relationships are applied in order of their alias, so when two of them set the same setting,
+    /// the collection whose alias sorts last takes precedence.
+    /// Settings required by the connector itself cannot be overridden
+    pub fn query_settings(&self, request: &models::QueryRequest) -> Vec<(String, String)> {
+        let relationship_collections: BTreeSet<&CollectionName> = request
+            .collection_relationships
+            .values()
+            .map(|relationship| &relationship.target_collection)
+            .filter(|collection| *collection != &request.collection)
+            .collect();
+        let collections = relationship_collections
+            .into_iter()
+            .chain(iter::once(&request.collection));
+
+        let mut settings = self.settings.clone();
+
+        for collection in collections {
+            if let Some(collection_settings) = self.collection_settings(collection) {
+                settings.extend(collection_settings.clone());
+            }
+        }
+
+        // accept ISO-8601 timestamps with offsets as input
+        settings.insert(
+            "date_time_input_format".to_owned(),
+            "best_effort".to_owned(),
+        );
+        // output timestamps as ISO-8601 with an offset
+        settings.insert("date_time_output_format".to_owned(), "iso".to_owned());
+
+        if let Some(timezone) = &self.timezone {
+            settings.insert("session_timezone".to_owned(), timezone.to_owned());
+        }
+
+        settings.into_iter().collect()
+    }
+    fn collection_settings(
+        &self,
+        collection: &CollectionName,
+    ) -> Option<&BTreeMap<String, String>> {
+        self.tables
+            .get(collection)
+            .map(|table| &table.settings)
+            .or_else(|| self.queries.get(collection).map(|query| &query.settings))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -34,9 +149,62 @@ pub struct TableType {
 
 #[derive(Debug, Default, Clone)]
 pub struct ConnectionConfig {
-    pub username: String,
-    pub password: String,
-    pub url: String,
+    pub credentials: Credentials,
+    /// how often to re-read credentials from files, so rotated credentials take effect
+    pub credentials_reload_interval: Duration,
+    /// one or more ClickHouse endpoints, all serving the same data
+    pub urls: Vec<String>,
+    pub load_balancing: LoadBalancing,
+    /// how long to stop sending queries to an endpoint after a connection failure
+    pub endpoint_cooldown: Duration,
+    /// request compressed responses, and compress large request bodies
+    pub compression: bool,
+    pub connect_timeout: Option<Duration>,
+    /// timeout for the whole request, including reading the response
+    pub request_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub tcp_keepalive: Option<Duration>,
+    /// send requests through this proxy, instead of the proxy set by the system environment, if any
+    pub proxy: Option<reqwest::Url>,
+    /// hosts that should not be reached through the proxy, in NO_PROXY format
+    pub no_proxy: Option<String>,
+    pub user_agent: Option<String>,
+    pub tls: TlsConfig,
+    /// how many times to attempt a query, retrying transient failures
+    pub max_attempts: u32,
+}
+
+/// How to pick an endpoint for each query, when multiple urls are configured
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LoadBalancing {
+    #[default]
+    RoundRobin,
+    /// the endpoint with the fewest requests in flight
+    LeastInFlight,
+}
+
+impl FromStr for LoadBalancing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(Self::RoundRobin),
+            "least_in_flight" => Ok(Self::LeastInFlight),
+            _ => Err(format!(
+                "unknown load balancing strategy {s}, expected round_robin or least_in_flight"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct TlsConfig {
+    /// additional trusted root certificates, as a PEM bundle
+    pub ca_certificates: Option<Vec<u8>>,
+    /// client certificate and private key for mutual TLS, as PEM
+    pub client_identity: Option<Vec<u8>>,
+    /// accept invalid server certificates. Only meant for development
+    pub insecure_skip_verify: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -48,16 +216,56 @@ pub struct TableConfig {
     /// Comments are sourced from the database table comment
     pub comment: Option<String>,
     pub primary_key: Option<PrimaryKey>,
+    /// the sampling key, if the table engine supports sampling
+    pub sampling_key: Option<String>,
     pub arguments: BTreeMap<ArgumentName, ClickHouseDataType>,
+    /// whether to read the table using the FINAL modifier
+    pub r#final: bool,
+    pub prewhere: PrewhereConfig,
+    pub settings: BTreeMap<String, String>,
     // this key coresponds to a return type definition in the config table types
     pub return_type: ObjectTypeName,
 }
 
+/// Optional argument for tables with a sampling key, the ratio of rows to sample
+pub const SAMPLE_RATIO_ARGUMENT: &str = "_sample_ratio";
+/// Optional argument for tables with a sampling key, the offset of the sample as a ratio
+pub const SAMPLE_OFFSET_ARGUMENT: &str = "_sample_offset";
+
+impl TableConfig {
+    /// The sampling arguments accepted by this table, in addition to any view parameters.
+    /// Only tables with a sampling key can be sampled
+    pub fn sample_arguments(&self) -> Vec<(ArgumentName, ClickHouseDataType)> {
+        if self.sampling_key.is_none() {
+            return vec![];
+        }
+
+        [SAMPLE_RATIO_ARGUMENT, SAMPLE_OFFSET_ARGUMENT]
+            .into_iter()
+            .map(|name| {
+                (
+                    name.to_owned().into(),
+                    ClickHouseDataType::Nullable(Box::new(ClickHouseDataType::Float64)),
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TenancyConfig {
+    pub argument: ArgumentName,
+    pub columns: BTreeMap<CollectionName, FieldName>,
+    /// the data type shared by all tenant columns, without Nullable or LowCardinality wrappers
+    pub data_type: ClickHouseDataType,
+}
+
 #[derive(Debug, Clone)]
 pub struct ParameterizedQueryConfig {
     pub exposed_as: ParameterizedQueryExposedAs,
     pub comment: Option<String>,
     pub query: ParameterizedQuery,
+    pub settings: BTreeMap<String, String>,
     pub return_type: ObjectTypeName,
 }
 
@@ -65,6 +273,8 @@ pub struct ParameterizedQueryConfig {
 pub enum ConfigurationError {
     #[error("missing required environment variable: {0}")]
     MissingEnvironmentVariable(String),
+    #[error("invalid value for environment variable {name}: {message}")]
+    InvalidEnvironmentVariable { name: String, message: String },
     #[error("could not find configuration file: {0}")]
     FileNotFound(PathBuf),
     #[error("error processing configuration: {0}")]
@@ -93,52 +303,268 @@ pub enum ConfigurationError {
 pub struct ConfigurationEnvironment {
     url: Option<String>,
     username: Option<String>,
+    username_file: Option<String>,
     password: Option<String>,
+    password_file: Option<String>,
+    bearer_token: Option<String>,
+    bearer_token_file: Option<String>,
+    credentials_reload_interval: Option<String>,
+    compression: Option<String>,
+    connect_timeout: Option<String>,
+    request_timeout: Option<String>,
+    pool_max_idle_per_host: Option<String>,
+    tcp_keepalive: Option<String>,
+    proxy: Option<String>,
+    no_proxy: Option<String>,
+    user_agent: Option<String>,
+    tls_ca_cert: Option<String>,
+    tls_client_cert: Option<String>,
+    tls_client_key: Option<String>,
+    tls_insecure_skip_verify: Option<String>,
+    max_attempts: Option<String>,
+    load_balancing: Option<String>,
+    endpoint_cooldown: Option<String>,
 }
 
 impl ConfigurationEnvironment {
     pub fn from_environment() -> Self {
-        Self {
-            url: env::var("CLICKHOUSE_URL").ok(),
-            username: env::var("CLICKHOUSE_USERNAME").ok(),
-            password: env::var("CLICKHOUSE_PASSWORD").ok(),
-        }
+        Self::from_simulated_environment(env::vars().collect())
     }
     pub fn from_simulated_environment(env: HashMap<String, String>) -> Self {
         Self {
             url: env.get("CLICKHOUSE_URL").cloned(),
             username: env.get("CLICKHOUSE_USERNAME").cloned(),
+            username_file: env.get("CLICKHOUSE_USERNAME_FILE").cloned(),
             password: env.get("CLICKHOUSE_PASSWORD").cloned(),
+            password_file: env.get("CLICKHOUSE_PASSWORD_FILE").cloned(),
+            bearer_token: env.get("CLICKHOUSE_BEARER_TOKEN").cloned(),
+            bearer_token_file: env.get("CLICKHOUSE_BEARER_TOKEN_FILE").cloned(),
+            credentials_reload_interval: env.get("CLICKHOUSE_CREDENTIALS_RELOAD_INTERVAL").cloned(),
+            compression: env.get("CLICKHOUSE_COMPRESSION").cloned(),
+            connect_timeout: env.get("CLICKHOUSE_CONNECT_TIMEOUT").cloned(),
+            request_timeout: env.get("CLICKHOUSE_REQUEST_TIMEOUT").cloned(),
+            pool_max_idle_per_host: env.get("CLICKHOUSE_POOL_MAX_IDLE_PER_HOST").cloned(),
+            tcp_keepalive: env.get("CLICKHOUSE_TCP_KEEPALIVE").cloned(),
+            proxy: env.get("CLICKHOUSE_PROXY").cloned(),
+            no_proxy: env.get("CLICKHOUSE_NO_PROXY").cloned(),
+            user_agent: env.get("CLICKHOUSE_USER_AGENT").cloned(),
+            tls_ca_cert: env.get("CLICKHOUSE_TLS_CA_CERT").cloned(),
+            tls_client_cert: env.get("CLICKHOUSE_TLS_CLIENT_CERT").cloned(),
+            tls_client_key: env.get("CLICKHOUSE_TLS_CLIENT_KEY").cloned(),
+            tls_insecure_skip_verify: env.get("CLICKHOUSE_TLS_INSECURE_SKIP_VERIFY").cloned(),
+            max_attempts: env.get("CLICKHOUSE_MAX_ATTEMPTS").cloned(),
+            load_balancing: env.get("CLICKHOUSE_LOAD_BALANCING").cloned(),
+            endpoint_cooldown: env.get("CLICKHOUSE_ENDPOINT_COOLDOWN").cloned(),
         }
     }
 }
 
+/// Parse an optional environment variable
+fn parse_environment_variable<T>(
+    name: &str,
+    value: &Option<String>,
+) -> Result<Option<T>, ConfigurationError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .as_ref()
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|err| ConfigurationError::InvalidEnvironmentVariable {
+                    name: name.to_owned(),
+                    message: format!("{err}"),
+                })
+        })
+        .transpose()
+}
+
 pub fn get_connection_configuration(
     env: &ConfigurationEnvironment,
 ) -> Result<ConnectionConfig, ConfigurationError> {
-    let url = env
+    // multiple endpoints may be configured as a comma separated list
+    let urls: Vec<String> = env
         .url
-        .to_owned()
+        .as_ref()
         .ok_or(ConfigurationError::MissingEnvironmentVariable(
             "CLICKHOUSE_URL".into(),
-        ))?;
-    let username =
-        env.username
-            .to_owned()
-            .ok_or(ConfigurationError::MissingEnvironmentVariable(
-                "CLICKHOUSE_USERNAME".into(),
-            ))?;
-    let password =
-        env.password
-            .to_owned()
-            .ok_or(ConfigurationError::MissingEnvironmentVariable(
-                "CLICKHOUSE_PASSWORD".into(),
-            ))?;
+        ))?
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(ToOwned::to_owned)
+        .collect();
+
+    if urls.is_empty() {
+        return Err(ConfigurationError::InvalidEnvironmentVariable {
+            name: "CLICKHOUSE_URL".to_owned(),
+            message: "at least one url is required".to_owned(),
+        });
+    }
+
+    // a bearer token replaces the username and password, if set
+    let credentials = if env.bearer_token.is_some() || env.bearer_token_file.is_some() {
+        let token = get_secret(
+            "CLICKHOUSE_BEARER_TOKEN",
+            &env.bearer_token,
+            &env.bearer_token_file,
+        )?;
+
+        Credentials::bearer(token)
+    } else {
+        let username = get_secret("CLICKHOUSE_USERNAME", &env.username, &env.username_file)?;
+        let password = get_secret("CLICKHOUSE_PASSWORD", &env.password, &env.password_file)?;
+
+        Credentials::basic(username, password)
+    }
+    .map_err(ConfigurationError::IoError)?;
+    let credentials_reload_interval = parse_environment_variable(
+        "CLICKHOUSE_CREDENTIALS_RELOAD_INTERVAL",
+        &env.credentials_reload_interval,
+    )?
+    .map(Duration::from_secs)
+    .unwrap_or(Duration::from_secs(60));
+
+    if credentials_reload_interval.is_zero() {
+        return Err(ConfigurationError::InvalidEnvironmentVariable {
+            name: "CLICKHOUSE_CREDENTIALS_RELOAD_INTERVAL".to_owned(),
+            message: "must be greater than zero".to_owned(),
+        });
+    }
+
+    let compression =
+        parse_environment_variable("CLICKHOUSE_COMPRESSION", &env.compression)?.unwrap_or(false);
+
+    // durations are configured in seconds
+    let connect_timeout =
+        parse_environment_variable("CLICKHOUSE_CONNECT_TIMEOUT", &env.connect_timeout)?
+            .map(Duration::from_secs);
+    let request_timeout =
+        parse_environment_variable("CLICKHOUSE_REQUEST_TIMEOUT", &env.request_timeout)?
+            .map(Duration::from_secs);
+    let pool_max_idle_per_host = parse_environment_variable(
+        "CLICKHOUSE_POOL_MAX_IDLE_PER_HOST",
+        &env.pool_max_idle_per_host,
+    )?;
+    let tcp_keepalive = parse_environment_variable("CLICKHOUSE_TCP_KEEPALIVE", &env.tcp_keepalive)?
+        .map(Duration::from_secs);
+    let proxy = parse_environment_variable("CLICKHOUSE_PROXY", &env.proxy)?;
+    let max_attempts =
+        parse_environment_variable("CLICKHOUSE_MAX_ATTEMPTS", &env.max_attempts)?.unwrap_or(3);
+    let load_balancing =
+        parse_environment_variable("CLICKHOUSE_LOAD_BALANCING", &env.load_balancing)?
+            .unwrap_or_default();
+    let endpoint_cooldown =
+        parse_environment_variable("CLICKHOUSE_ENDPOINT_COOLDOWN", &env.endpoint_cooldown)?
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(30));
 
     Ok(ConnectionConfig {
-        url,
-        username,
-        password,
+        credentials,
+        credentials_reload_interval,
+        urls,
+        load_balancing,
+        endpoint_cooldown,
+        compression,
+        connect_timeout,
+        request_timeout,
+        pool_max_idle_per_host,
+        tcp_keepalive,
+        proxy,
+        no_proxy: env.no_proxy.to_owned(),
+        user_agent: env.user_agent.to_owned(),
+        tls: get_tls_configuration(env)?,
+        max_attempts,
+    })
+}
+
+/// A secret may be set directly, or as the path to a file containing the secret.
+/// The file takes precedence, so deployments that always set the variable can still use a file
+fn get_secret(
+    value_name: &str,
+    value: &Option<String>,
+    file: &Option<String>,
+) -> Result<Secret, ConfigurationError> {
+    match (value, file) {
+        (_, Some(file)) => Ok(Secret::File(file.into())),
+        (Some(value), None) => Ok(Secret::Value(value.to_owned())),
+        (None, None) => Err(ConfigurationError::MissingEnvironmentVariable(
+            value_name.to_owned(),
+        )),
+    }
+}
+
+/// Read and validate certificate files, so misconfiguration is reported at startup
+fn get_tls_configuration(env: &ConfigurationEnvironment) -> Result<TlsConfig, ConfigurationError> {
+    let read_file = |name: &str, path: &str| {
+        std::fs::read(path).map_err(|err| ConfigurationError::InvalidEnvironmentVariable {
+            name: name.to_owned(),
+            message: format!("unable to read file {path}: {err}"),
+        })
+    };
+
+    let ca_certificates = env
+        .tls_ca_cert
+        .as_ref()
+        .map(|path| {
+            let pem = read_file("CLICKHOUSE_TLS_CA_CERT", path)?;
+
+            match reqwest::Certificate::from_pem_bundle(&pem) {
+                Ok(certificates) if !certificates.is_empty() => Ok(pem),
+                Ok(_) => Err(ConfigurationError::InvalidEnvironmentVariable {
+                    name: "CLICKHOUSE_TLS_CA_CERT".to_owned(),
+                    message: format!("no certificates found in {path}"),
+                }),
+                Err(err) => Err(ConfigurationError::InvalidEnvironmentVariable {
+                    name: "CLICKHOUSE_TLS_CA_CERT".to_owned(),
+                    message: format!("unable to parse certificates in {path}: {err}"),
+                }),
+            }
+        })
+        .transpose()?;
+
+    let client_identity = match (&env.tls_client_cert, &env.tls_client_key) {
+        (None, None) => None,
+        (Some(_), None) => {
+            return Err(ConfigurationError::MissingEnvironmentVariable(
+                "CLICKHOUSE_TLS_CLIENT_KEY".into(),
+            ))
+        }
+        (None, Some(_)) => {
+            return Err(ConfigurationError::MissingEnvironmentVariable(
+                "CLICKHOUSE_TLS_CLIENT_CERT".into(),
+            ))
+        }
+        (Some(cert_path), Some(key_path)) => {
+            let mut pem = read_file("CLICKHOUSE_TLS_CLIENT_CERT", cert_path)?;
+            pem.push(b'\n');
+            pem.extend(read_file("CLICKHOUSE_TLS_CLIENT_KEY", key_path)?);
+
+            reqwest::Identity::from_pem(&pem).map_err(|err| {
+                ConfigurationError::InvalidEnvironmentVariable {
+                    name: "CLICKHOUSE_TLS_CLIENT_CERT".to_owned(),
+                    message: format!(
+                        "unable to parse client certificate {cert_path} and key {key_path}: {err}"
+                    ),
+                }
+            })?;
+
+            Some(pem)
+        }
+    };
+
+    let insecure_skip_verify = parse_environment_variable(
+        "CLICKHOUSE_TLS_INSECURE_SKIP_VERIFY",
+        &env.tls_insecure_skip_verify,
+    )?
+    .unwrap_or(false);
+
+    Ok(TlsConfig {
+        ca_certificates,
+        client_identity,
+        insecure_skip_verify,
     })
 }
 
@@ -148,7 +574,7 @@ pub async fn read_server_config(
 ) -> Result<ServerConfig, ConfigurationError> {
     let file_path = configuration_dir.join(CONFIG_FILE_NAME);
 
-    let connection = get_connection_configuration(environment)?;
+    let mut connection = get_connection_configuration(environment)?;
 
     let config_file = fs::read_to_string(&file_path)
         .await
@@ -166,6 +592,41 @@ pub async fn read_server_config(
         }
     })?;
 
+    // tables and queries are both exposed as collections, and share a namespace
+    if let Some(query_alias) = config
+        .queries
+        .keys()
+        .find(|query_alias| config.tables.contains_key(*query_alias))
+    {
+        return Err(ConfigurationError::ValidateError {
+            file_path: file_path.to_owned(),
+            node_path: vec!["queries".to_string(), query_alias.to_string()],
+            message: format!(
+                "Query alias {query_alias} conflicts with a table of the same name. Aliases must be unique"
+            ),
+        });
+    }
+
+    if let Some(http_client) = &config.http_client {
+        apply_http_client_config(&mut connection, http_client, &file_path)?;
+    }
+
+    validate_settings(&config.settings, &file_path, &["settings"])?;
+    for (table_alias, table_config) in &config.tables {
+        validate_settings(
+            &table_config.settings,
+            &file_path,
+            &["tables", table_alias.inner(), "settings"],
+        )?;
+    }
+    for (query_alias, query_config) in &config.queries {
+        validate_settings(
+            &query_config.settings,
+            &file_path,
+            &["queries", query_alias.inner(), "settings"],
+        )?;
+    }
+
     let table_types = config
         .tables
         .iter()
@@ -221,6 +682,10 @@ pub async fn read_server_config(
                     schema: table_config.schema.to_owned(),
                     comment: table_config.comment.to_owned(),
                     primary_key: table_config.primary_key.to_owned(),
+                    sampling_key: table_config.sampling_key.to_owned(),
+                    r#final: table_config.r#final,
+                    prewhere: table_config.prewhere.to_owned(),
+                    settings: table_config.settings.to_owned(),
                     return_type: match &table_config.return_type {
                         ReturnType::Definition { .. } => table_alias.to_string().into(),
                         ReturnType::TableReference {
@@ -282,6 +747,7 @@ pub async fn read_server_config(
             exposed_as: query_config.exposed_as.to_owned(),
             comment: query_config.comment.to_owned(),
             query,
+            settings: query_config.settings.to_owned(),
             return_type: match query_config.return_type {
                 ReturnType::Definition { .. } => query_alias.to_string().into(),
                 ReturnType::TableReference {
@@ -296,19 +762,321 @@ pub async fn read_server_config(
         queries.insert(query_alias.to_owned(), query_definition);
     }
 
+    let tenancy = config
+        .tenancy
+        .as_ref()
+        .map(|tenancy| validate_tenancy(tenancy, &tables, &table_types, &queries, &file_path))
+        .transpose()?;
+
     let config = ServerConfig {
         connection,
-        // hardcoding separator for now, to avoid prematurely exposing configuration options we may not want to keep
-        // if we make this configurable, we must default to this separator when the option is not provided
-        namespace_separator: ".".to_string(),
+        namespace_separator: NAMESPACE_SEPARATOR.to_string(),
         table_types,
         tables,
         queries,
+        timezone: config.timezone.to_owned(),
+        scalar_types: config.scalar_types.to_owned(),
+        settings: config.settings.to_owned(),
+        max_response_bytes: config.max_response_bytes,
+        role_forwarding: config.role_forwarding.to_owned(),
+        tenancy,
+        database_routing: config.database_routing.to_owned(),
     };
 
+    for (argument_name, _) in config.request_arguments() {
+        let conflict = config
+            .tables
+            .iter()
+            .find(|(_, table)| {
+                table.arguments.contains_key(&argument_name)
+                    || table
+                        .sample_arguments()
+                        .iter()
+                        .any(|(name, _)| name == &argument_name)
+            })
+            .map(|(table_alias, _)| vec!["tables".to_string(), table_alias.to_string()])
+            .or_else(|| {
+                config
+                    .queries
+                    .iter()
+                    // queries read the tenant from the request argument, see validate_tenancy
+                    .filter(|_| {
+                        config.tenancy.as_ref().map(|tenancy| &tenancy.argument)
+                            != Some(&argument_name)
+                    })
+                    .find(|(_, query)| {
+                        query.query.elements.iter().any(|element| {
+                            matches!(
+                                element,
+                                ParameterizedQueryElement::Parameter(Parameter { name, .. })
+                                    if name.value() == argument_name.inner()
+                            )
+                        })
+                    })
+                    .map(|(query_alias, _)| vec!["queries".to_string(), query_alias.to_string()])
+            });
+
+        if let Some(node_path) = conflict {
+            return Err(ConfigurationError::ValidateError {
+                file_path: file_path.to_owned(),
+                node_path,
+                message: format!(
+                    "Argument {argument_name} conflicts with an argument of the same name accepted by every collection. Rename one of the conflicting arguments"
+                ),
+            });
+        }
+    }
+
+    validate_type_names(&config).map_err(
+        |TypeNameCollision {
+             type_name,
+             node_path,
+             existing_node_path,
+         }| ConfigurationError::ValidateError {
+            file_path: file_path.to_owned(),
+            node_path,
+            message: format!(
+                "Type name {type_name} conflicts with a different type of the same name defined at {}. Rename one of the conflicting tables, queries or columns",
+                display_period_separated(&existing_node_path)
+            ),
+        },
+    )?;
+
     Ok(config)
 }
 
+/// Fill in HTTP client options that were not set using environment variables from the configuration file.
+/// Environment variables take precedence, so a deployment can override options without changing the configuration
+pub fn apply_http_client_config(
+    connection: &mut ConnectionConfig,
+    http_client: &HttpClientConfig,
+    file_path: &Path,
+) -> Result<(), ConfigurationError> {
+    let HttpClientConfig {
+        connect_timeout,
+        request_timeout,
+        pool_max_idle_per_host,
+        tcp_keepalive,
+        proxy,
+        no_proxy,
+        user_agent,
+    } = http_client;
+
+    // durations are configured in seconds
+    connection.connect_timeout = connection
+        .connect_timeout
+        .or(connect_timeout.map(Duration::from_secs));
+    connection.request_timeout = connection
+        .request_timeout
+        .or(request_timeout.map(Duration::from_secs));
+    connection.pool_max_idle_per_host = connection
+        .pool_max_idle_per_host
+        .or(*pool_max_idle_per_host);
+    connection.tcp_keepalive = connection
+        .tcp_keepalive
+        .or(tcp_keepalive.map(Duration::from_secs));
+
+    if connection.proxy.is_none() {
+        connection.proxy = proxy
+            .as_ref()
+            .map(|proxy| {
+                proxy
+                    .parse()
+                    .map_err(|err| ConfigurationError::ValidateError {
+                        file_path: file_path.to_owned(),
+                        node_path: vec!["http_client".to_string(), "proxy".to_string()],
+                        message: format!("Invalid proxy url: {err}"),
+                    })
+            })
+            .transpose()?;
+    }
+    if connection.no_proxy.is_none() {
+        connection.no_proxy = no_proxy.to_owned();
+    }
+    if connection.user_agent.is_none() {
+        connection.user_agent = user_agent.to_owned();
+    }
+
+    Ok(())
+}
+
+/// Url parameters set by the connector itself, or interpreted by the ClickHouse HTTP interface rather than as settings
+const RESERVED_SETTINGS: &[&str] = &[
+    "date_time_input_format",
+    "date_time_output_format",
+    "session_timezone",
+    "query_id",
+    "log_comment",
+    "role",
+    "enable_http_compression",
+    "replace_running_query",
+    "query",
+    "database",
+    "user",
+    "password",
+    "default_format",
+    "session_id",
+    "quota_key",
+    "compress",
+    "decompress",
+    "buffer_size",
+    "wait_end_of_query",
+    // overflowing results would be truncated rather than rejected, producing invalid JSON.
+    // Use max_response_bytes instead
+    "max_result_bytes",
+    "result_overflow_mode",
+];
+
+/// Setting names must be valid identifiers, and must not be reserved.
+/// Query parameters use the `param_` prefix, and so cannot be set as settings either
+fn validate_settings(
+    settings: &BTreeMap<String, String>,
+    file_path: &Path,
+    node_path: &[&str],
+) -> Result<(), ConfigurationError> {
+    for name in settings.keys() {
+        let is_identifier = name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+        let message = if !is_identifier {
+            format!("Unknown setting {name}: setting names may only contain letters, digits and underscores")
+        } else if RESERVED_SETTINGS.contains(&name.as_str()) || name.starts_with("param_") {
+            format!("Setting {name} is reserved, and cannot be configured")
+        } else {
+            continue;
+        };
+
+        return Err(ConfigurationError::ValidateError {
+            file_path: file_path.to_owned(),
+            node_path: node_path
+                .iter()
+                .map(|segment| segment.to_string())
+                .chain(iter::once(name.to_owned()))
+                .collect(),
+            message,
+        });
+    }
+
+    Ok(())
+}
+
+/// Tenant columns must exist, and share a data type, as they are compared to the same argument value.
+/// Queries exposed as collections cannot be restricted to a tenant by the connector,
+/// so they must declare the tenant argument as a parameter, and filter by it themselves
+fn validate_tenancy(
+    tenancy: &TenancyConfigFile,
+    tables: &BTreeMap<CollectionName, TableConfig>,
+    table_types: &BTreeMap<ObjectTypeName, TableType>,
+    queries: &BTreeMap<CollectionName, ParameterizedQueryConfig>,
+    file_path: &Path,
+) -> Result<TenancyConfig, ConfigurationError> {
+    let mut data_type: Option<ClickHouseDataType> = None;
+
+    for (table_alias, column_alias) in &tenancy.columns {
+        let validate_error = |message: String| ConfigurationError::ValidateError {
+            file_path: file_path.to_owned(),
+            node_path: vec![
+                "tenancy".to_string(),
+                "columns".to_string(),
+                table_alias.to_string(),
+            ],
+            message,
+        };
+
+        let column_type = tables
+            .get(table_alias)
+            .ok_or_else(|| validate_error(format!("Unknown table {table_alias}")))?
+            .return_type
+            .to_owned();
+        let column_type = table_types
+            .get(&column_type)
+            .and_then(|table_type| table_type.columns.get(column_alias))
+            .ok_or_else(|| {
+                validate_error(format!(
+                    "Unknown column {column_alias} for table {table_alias}"
+                ))
+            })?;
+        let column_type = base_data_type(column_type);
+
+        match &data_type {
+            Some(data_type) if data_type != column_type => {
+                return Err(validate_error(format!(
+                    "Tenant column {column_alias} has type {column_type}, but other tenant columns have type {data_type}"
+                )))
+            }
+            Some(_) => {}
+            None => data_type = Some(column_type.to_owned()),
+        }
+    }
+
+    let data_type = data_type.unwrap_or(ClickHouseDataType::String);
+
+    for (query_alias, query) in queries {
+        if query.exposed_as != ParameterizedQueryExposedAs::Collection {
+            continue;
+        }
+
+        let validate_error = |message: String| ConfigurationError::ValidateError {
+            file_path: file_path.to_owned(),
+            node_path: vec!["queries".to_string(), query_alias.to_string()],
+            message,
+        };
+
+        let tenant_parameters = query
+            .query
+            .elements
+            .iter()
+            .filter_map(|element| match element {
+                ParameterizedQueryElement::Parameter(parameter)
+                    if parameter.name.value() == tenancy.argument.inner() =>
+                {
+                    Some(parameter)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if tenant_parameters.is_empty() {
+            return Err(validate_error(format!(
+                "Query {query_alias} does not declare the tenant parameter {{{}: {data_type}}}. Queries are not restricted to a tenant by the connector, and must filter by the tenant parameter",
+                tenancy.argument
+            )));
+        }
+
+        for Parameter { r#type, .. } in tenant_parameters {
+            match r#type {
+                ParameterType::DataType(parameter_type)
+                    if base_data_type(parameter_type) == &data_type => {}
+                _ => {
+                    return Err(validate_error(format!(
+                        "Tenant parameter {} has type {}, but tenant columns have type {data_type}",
+                        tenancy.argument, r#type
+                    )))
+                }
+            }
+        }
+    }
+
+    Ok(TenancyConfig {
+        argument: tenancy.argument.to_owned(),
+        columns: tenancy.columns.to_owned(),
+        data_type,
+    })
+}
+
+/// Strip Nullable and LowCardinality wrappers, which do not affect the values a column can be compared to
+fn base_data_type(data_type: &ClickHouseDataType) -> &ClickHouseDataType {
+    match data_type {
+        ClickHouseDataType::Nullable(inner) | ClickHouseDataType::LowCardinality(inner) => {
+            base_data_type(inner)
+        }
+        _ => data_type,
+    }
+}
+
 fn validate_and_parse_return_type(
     return_type: &ReturnType,
     config: &ServerConfigFile,
@@ -380,3 +1148,255 @@ fn validate_and_parse_return_type(
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment(variables: &[(&str, &str)]) -> ConfigurationEnvironment {
+        ConfigurationEnvironment::from_simulated_environment(
+            [
+                ("CLICKHOUSE_URL", "http://localhost:8123"),
+                ("CLICKHOUSE_USERNAME", "default"),
+                ("CLICKHOUSE_PASSWORD", ""),
+            ]
+            .iter()
+            .chain(variables)
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        )
+    }
+
+    fn http_client_config() -> HttpClientConfig {
+        HttpClientConfig {
+            connect_timeout: Some(10),
+            request_timeout: Some(300),
+            pool_max_idle_per_host: Some(4),
+            tcp_keepalive: Some(60),
+            proxy: Some("http://file-proxy:3128".to_owned()),
+            no_proxy: Some("file.internal".to_owned()),
+            user_agent: Some("file-agent".to_owned()),
+        }
+    }
+
+    #[test]
+    fn apply_http_client_config_when_environment_is_unset() {
+        let mut connection = get_connection_configuration(&environment(&[]))
+            .expect("Should read connection configuration");
+
+        apply_http_client_config(&mut connection, &http_client_config(), Path::new("test"))
+            .expect("Should apply http client configuration");
+
+        assert_eq!(connection.connect_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(connection.request_timeout, Some(Duration::from_secs(300)));
+        assert_eq!(connection.pool_max_idle_per_host, Some(4));
+        assert_eq!(connection.tcp_keepalive, Some(Duration::from_secs(60)));
+        assert_eq!(
+            connection.proxy.as_ref().map(|proxy| proxy.as_str()),
+            Some("http://file-proxy:3128/")
+        );
+        assert_eq!(connection.no_proxy.as_deref(), Some("file.internal"));
+        assert_eq!(connection.user_agent.as_deref(), Some("file-agent"));
+    }
+    #[test]
+    fn environment_takes_precedence_over_http_client_config() {
+        let mut connection = get_connection_configuration(&environment(&[
+            ("CLICKHOUSE_CONNECT_TIMEOUT", "5"),
+            ("CLICKHOUSE_REQUEST_TIMEOUT", "30"),
+            ("CLICKHOUSE_POOL_MAX_IDLE_PER_HOST", "8"),
+            ("CLICKHOUSE_TCP_KEEPALIVE", "15"),
+            ("CLICKHOUSE_PROXY", "http://env-proxy:3128"),
+            ("CLICKHOUSE_NO_PROXY", "env.internal"),
+            ("CLICKHOUSE_USER_AGENT", "env-agent"),
+        ]))
+        .expect("Should read connection configuration");
+
+        apply_http_client_config(&mut connection, &http_client_config(), Path::new("test"))
+            .expect("Should apply http client configuration");
+
+        assert_eq!(connection.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(connection.request_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(connection.pool_max_idle_per_host, Some(8));
+        assert_eq!(connection.tcp_keepalive, Some(Duration::from_secs(15)));
+        assert_eq!(
+            connection.proxy.as_ref().map(|proxy| proxy.as_str()),
+            Some("http://env-proxy:3128/")
+        );
+        assert_eq!(connection.no_proxy.as_deref(), Some("env.internal"));
+        assert_eq!(connection.user_agent.as_deref(), Some("env-agent"));
+    }
+    #[test]
+    fn reject_invalid_proxy_in_http_client_config() {
+        let http_client = HttpClientConfig {
+            proxy: Some("not a url".to_owned()),
+            ..http_client_config()
+        };
+
+        let mut connection = get_connection_configuration(&environment(&[]))
+            .expect("Should read connection configuration");
+        let error = apply_http_client_config(&mut connection, &http_client, Path::new("test"))
+            .expect_err("Invalid proxy should be rejected");
+        assert!(matches!(
+            error,
+            ConfigurationError::ValidateError { node_path, .. } if node_path == ["http_client", "proxy"]
+        ));
+
+        // the file is not used when the environment variable is set
+        let mut connection = get_connection_configuration(&environment(&[(
+            "CLICKHOUSE_PROXY",
+            "http://env-proxy:3128",
+        )]))
+        .expect("Should read connection configuration");
+        apply_http_client_config(&mut connection, &http_client, Path::new("test"))
+            .expect("Should apply http client configuration");
+    }
+    #[test]
+    fn reject_invalid_environment_variables() {
+        for (name, value) in [
+            ("CLICKHOUSE_CONNECT_TIMEOUT", "soon"),
+            ("CLICKHOUSE_PROXY", "not a url"),
+            ("CLICKHOUSE_MAX_ATTEMPTS", "-1"),
+        ] {
+            let error = get_connection_configuration(&environment(&[(name, value)]))
+                .expect_err("Invalid value should be rejected");
+
+            assert!(
+                matches!(&error, ConfigurationError::InvalidEnvironmentVariable { name: error_name, .. } if error_name == name),
+                "{name}: {error}"
+            );
+        }
+    }
+    #[test]
+    fn require_client_certificate_and_key_together() {
+        let error = get_connection_configuration(&environment(&[(
+            "CLICKHOUSE_TLS_CLIENT_CERT",
+            "/certs/client.crt",
+        )]))
+        .expect_err("Certificate without key should be rejected");
+        assert!(matches!(
+            error,
+            ConfigurationError::MissingEnvironmentVariable(name) if name == "CLICKHOUSE_TLS_CLIENT_KEY"
+        ));
+
+        let error = get_connection_configuration(&environment(&[(
+            "CLICKHOUSE_TLS_CLIENT_KEY",
+            "/certs/client.key",
+        )]))
+        .expect_err("Key without certificate should be rejected");
+        assert!(matches!(
+            error,
+            ConfigurationError::MissingEnvironmentVariable(name) if name == "CLICKHOUSE_TLS_CLIENT_CERT"
+        ));
+    }
+    #[test]
+    fn reject_unreadable_or_invalid_certificate_files() {
+        let missing_file = env::temp_dir().join(format!(
+            "ndc-clickhouse-missing-ca-{}.pem",
+            std::process::id()
+        ));
+        let invalid_file = env::temp_dir().join(format!(
+            "ndc-clickhouse-invalid-ca-{}.pem",
+            std::process::id()
+        ));
+        std::fs::write(&invalid_file, "not a certificate").expect("Should write file");
+
+        for (variables, expected_name, expected_message) in [
+            (
+                vec![("CLICKHOUSE_TLS_CA_CERT", missing_file.to_str().unwrap())],
+                "CLICKHOUSE_TLS_CA_CERT",
+                "unable to read file",
+            ),
+            (
+                vec![("CLICKHOUSE_TLS_CA_CERT", invalid_file.to_str().unwrap())],
+                "CLICKHOUSE_TLS_CA_CERT",
+                "no certificates found",
+            ),
+            (
+                vec![
+                    ("CLICKHOUSE_TLS_CLIENT_CERT", missing_file.to_str().unwrap()),
+                    ("CLICKHOUSE_TLS_CLIENT_KEY", invalid_file.to_str().unwrap()),
+                ],
+                "CLICKHOUSE_TLS_CLIENT_CERT",
+                "unable to read file",
+            ),
+            (
+                vec![
+                    ("CLICKHOUSE_TLS_CLIENT_CERT", invalid_file.to_str().unwrap()),
+                    ("CLICKHOUSE_TLS_CLIENT_KEY", invalid_file.to_str().unwrap()),
+                ],
+                "CLICKHOUSE_TLS_CLIENT_CERT",
+                "unable to parse client certificate",
+            ),
+            (
+                vec![("CLICKHOUSE_TLS_INSECURE_SKIP_VERIFY", "yes")],
+                "CLICKHOUSE_TLS_INSECURE_SKIP_VERIFY",
+                "",
+            ),
+        ] {
+            let error = get_connection_configuration(&environment(&variables))
+                .expect_err("Invalid TLS configuration should be rejected");
+
+            match error {
+                ConfigurationError::InvalidEnvironmentVariable { name, message } => {
+                    assert_eq!(name, expected_name);
+                    assert!(message.contains(expected_message), "{message}");
+                }
+                error => panic!("Unexpected error: {error}"),
+            }
+        }
+
+        std::fs::remove_file(&invalid_file).expect("Should remove file");
+    }
+    #[test]
+    fn reject_reserved_and_invalid_settings() {
+        for name in
+            RESERVED_SETTINGS
+                .iter()
+                .copied()
+                .chain(["param_artist", "max threads", "1st", ""])
+        {
+            let settings = BTreeMap::from([(name.to_owned(), "1".to_owned())]);
+
+            let error = validate_settings(
+                &settings,
+                Path::new("test"),
+                &["tables", "Artist", "settings"],
+            )
+            .expect_err("Setting should be rejected");
+
+            assert!(
+                matches!(
+                    &error,
+                    ConfigurationError::ValidateError { node_path, .. }
+                        if node_path == &["tables", "Artist", "settings", name]
+                ),
+                "{name}: {error}"
+            );
+        }
+
+        // url parameters interpreted by the HTTP interface, and settings that would truncate results
+        for name in [
+            "session_id",
+            "quota_key",
+            "compress",
+            "decompress",
+            "buffer_size",
+            "wait_end_of_query",
+            "max_result_bytes",
+            "result_overflow_mode",
+        ] {
+            assert!(RESERVED_SETTINGS.contains(&name), "{name}");
+        }
+    }
+    #[test]
+    fn accept_valid_settings() {
+        let settings = BTreeMap::from([
+            ("max_threads".to_owned(), "4".to_owned()),
+            ("_custom_setting".to_owned(), "value".to_owned()),
+            ("max_execution_time".to_owned(), "30".to_owned()),
+        ]);
+
+        validate_settings(&settings, Path::new("test"), &["settings"])
+            .expect("Settings should be accepted");
+    }
+}
@@ -21,6 +21,34 @@ pub struct ServerConfigFile {
     /// Note the names must not match table names
     #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
     pub queries: BTreeMap<CollectionName, ParameterizedQueryConfigFile>,
+    /// Optionally set the session timezone, eg. "UTC" or "Europe/Berlin"
+    /// This is used to interpret timestamps received without an offset, for DateTime columns that do not declare a timezone.
+    /// Defaults to the server timezone
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub timezone: Option<String>,
+    /// Optionally configure how ClickHouse data types are mapped to scalar types in the schema
+    #[serde(skip_serializing_if = "ScalarTypesConfig::is_default", default)]
+    pub scalar_types: ScalarTypesConfig,
+    /// ClickHouse settings applied to every query, eg. max_execution_time or max_rows_to_read
+    /// Tables and queries may override these with their own settings
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub settings: BTreeMap<String, String>,
+    /// The maximum size of a query response in bytes.
+    /// Queries with larger responses are cancelled and fail with an error. Defaults to no limit
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_response_bytes: Option<u64>,
+    /// Optionally apply a ClickHouse role supplied by each request, so row policies and quotas apply per end user
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub role_forwarding: Option<RoleForwardingConfig>,
+    /// Optionally restrict tables shared by multiple tenants to the rows of the tenant supplied by each request
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub tenancy: Option<TenancyConfigFile>,
+    /// Optionally route each request to one of several databases with identical schemas
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub database_routing: Option<DatabaseRoutingConfig>,
+    /// Optionally configure the HTTP client used to connect to ClickHouse
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub http_client: Option<HttpClientConfig>,
 }
 
 impl Default for ServerConfigFile {
@@ -29,10 +57,121 @@ impl Default for ServerConfigFile {
             schema: CONFIG_SCHEMA_FILE_NAME.to_string(),
             tables: Default::default(),
             queries: Default::default(),
+            timezone: None,
+            scalar_types: Default::default(),
+            settings: Default::default(),
+            max_response_bytes: None,
+            role_forwarding: None,
+            tenancy: None,
+            database_routing: None,
+            http_client: None,
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// Options for the HTTP client used to connect to ClickHouse.
+/// Each option can be overridden by the corresponding environment variable, eg. CLICKHOUSE_CONNECT_TIMEOUT
+pub struct HttpClientConfig {
+    /// Timeout in seconds for establishing a connection
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub connect_timeout: Option<u64>,
+    /// Timeout in seconds for a whole request, including reading the response. Queries that time out are killed
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub request_timeout: Option<u64>,
+    /// Maximum number of idle connections kept in the connection pool
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Interval in seconds between TCP keepalive probes
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub tcp_keepalive: Option<u64>,
+    /// Send requests through this proxy. Otherwise, the system proxy configuration is used
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub proxy: Option<String>,
+    /// Comma separated hosts that should not be reached through the proxy
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub no_proxy: Option<String>,
+    /// User agent sent to ClickHouse. Defaults to ndc-clickhouse/<version>
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// Every table and query accepts an additional argument naming the ClickHouse role to query with.
+/// The argument is typically set using an argument preset from a session variable.
+/// Queries without a role are rejected. The configured user must be granted every role that may be supplied
+pub struct RoleForwardingConfig {
+    /// The name of the argument supplying the role. Defaults to _role
+    #[serde(default = "default_role_argument")]
+    pub argument: ArgumentName,
+}
+
+fn default_role_argument() -> ArgumentName {
+    "_role".to_owned().into()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// Every table and query accepts an additional argument supplying the tenant.
+/// Tables with a tenant column only return rows where the column equals the supplied tenant,
+/// wherever they are referenced, including relationships and predicates.
+/// Queries referencing such tables without a tenant are rejected. Native queries are not restricted
+pub struct TenancyConfigFile {
+    /// The name of the argument supplying the tenant. Defaults to _tenant
+    #[serde(default = "default_tenant_argument")]
+    pub argument: ArgumentName,
+    /// The tenant column for each table shared by multiple tenants. The map key is the table alias, the value a column alias.
+    /// All tenant columns must have the same data type
+    pub columns: BTreeMap<CollectionName, FieldName>,
+}
+
+fn default_tenant_argument() -> ArgumentName {
+    "_tenant".to_owned().into()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// Every table and query accepts an additional argument naming the database to query.
+/// Tables in any of the allowed databases are read from the supplied database instead.
+/// Queries referencing such tables without a database, or with a database that is not allowed, are rejected
+pub struct DatabaseRoutingConfig {
+    /// The name of the argument supplying the database. Defaults to _database
+    #[serde(default = "default_database_argument")]
+    pub argument: ArgumentName,
+    /// The databases requests may be routed to
+    pub databases: Vec<String>,
+}
+
+fn default_database_argument() -> ArgumentName {
+    "_database".to_owned().into()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// LowCardinality wrappers are always removed from schema scalar types.
+/// The exact column type is still used when casting query results.
+pub struct ScalarTypesConfig {
+    /// Expose FixedString(N) columns as the String scalar type, rather than one scalar type per length.
+    /// Defaults to true
+    #[serde(default = "default_true")]
+    pub merge_fixed_string: bool,
+}
+
+impl ScalarTypesConfig {
+    fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl Default for ScalarTypesConfig {
+    fn default() -> Self {
+        Self {
+            merge_fixed_string: true,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct TableConfigFile {
     /// The table name
@@ -44,14 +183,55 @@ pub struct TableConfigFile {
     pub comment: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub primary_key: Option<PrimaryKey>,
+    /// The sampling key is sourced from the table engine.
+    /// Tables with a sampling key accept the optional `_sample_ratio` and `_sample_offset` arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampling_key: Option<String>,
     #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
     pub arguments: BTreeMap<ArgumentName, String>,
+    /// Read the table using the FINAL modifier, so rows are deduplicated or collapsed at query time.
+    /// Defaults to true for ReplacingMergeTree and CollapsingMergeTree tables when the table is first introspected
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub r#final: bool,
+    /// Configure which predicates are moved to PREWHERE
+    #[serde(skip_serializing_if = "PrewhereConfig::is_default", default)]
+    pub prewhere: PrewhereConfig,
+    /// ClickHouse settings applied to queries reading this table, overriding top level settings
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub settings: BTreeMap<String, String>,
     /// The map key is a column alias identifying the table and may be customized.
     /// It defaults to the table name.
     /// When the configuration is updated, the column is identified by name, and changes to the alias are preserved.
     pub return_type: ReturnType,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// Top level predicates comparing primary key columns to literal values are moved to PREWHERE,
+/// so ClickHouse can skip reading other columns for rows that do not match
+pub struct PrewhereConfig {
+    /// Set to false to never generate PREWHERE for this table. Defaults to true
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Additional columns, outside of the primary key, that are cheap and selective to filter on
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub columns: Vec<FieldName>,
+}
+
+impl PrewhereConfig {
+    fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl Default for PrewhereConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            columns: vec![],
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct PrimaryKey {
     pub name: String,
@@ -124,6 +304,9 @@ pub struct ParameterizedQueryConfigFile {
     pub comment: Option<String>,
     /// A relative path to a sql file
     pub file: String,
+    /// ClickHouse settings applied when running this query, overriding top level settings
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub settings: BTreeMap<String, String>,
     /// Either a type definition for the return type for this query,
     /// or a reference to another return type: either a table's alias,
     /// or another query's alias. If another query, that query must have a return type definition.
@@ -0,0 +1,280 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+/// A secret value, either set directly or read from a file.
+/// Secrets read from files may be rotated, and are re-read when credentials are reloaded
+#[derive(Debug, Clone)]
+pub enum Secret {
+    Value(String),
+    File(PathBuf),
+}
+
+impl Default for Secret {
+    fn default() -> Self {
+        Self::Value(String::new())
+    }
+}
+
+impl Secret {
+    /// Read the secret, blocking the current thread. Used when the configuration is read at startup
+    fn read(&self) -> io::Result<String> {
+        match self {
+            Secret::Value(value) => Ok(value.to_owned()),
+            Secret::File(path) => Self::from_file_content(path, std::fs::read_to_string(path)),
+        }
+    }
+    /// Read the secret without blocking the runtime. Used when reloading credentials
+    async fn read_async(&self) -> io::Result<String> {
+        match self {
+            Secret::Value(value) => Ok(value.to_owned()),
+            Secret::File(path) => {
+                Self::from_file_content(path, tokio::fs::read_to_string(path).await)
+            }
+        }
+    }
+    fn from_file_content(path: &Path, content: io::Result<String>) -> io::Result<String> {
+        let content = content.map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("unable to read {}: {err}", path.display()),
+            )
+        })?;
+
+        // files written by orchestrators and editors often end with a newline, which is not part of the secret
+        let content = content.trim_end_matches(['\r', '\n']);
+
+        // a file that is being rotated may be read after it was truncated, but before the new secret was written
+        if content.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is empty", path.display()),
+            ));
+        }
+
+        Ok(content.to_owned())
+    }
+    fn is_file(&self) -> bool {
+        matches!(self, Secret::File(_))
+    }
+}
+
+/// Credentials used to authenticate with ClickHouse.
+/// The current values are shared by all clones, so reloading them affects every holder of the configuration
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    sources: CredentialSources,
+    current: Arc<RwLock<Authentication>>,
+}
+
+#[derive(Debug, Clone)]
+enum CredentialSources {
+    Basic { username: Secret, password: Secret },
+    Bearer { token: Secret },
+}
+
+impl Default for CredentialSources {
+    fn default() -> Self {
+        Self::Basic {
+            username: Secret::default(),
+            password: Secret::default(),
+        }
+    }
+}
+
+impl CredentialSources {
+    fn read(&self) -> io::Result<Authentication> {
+        match self {
+            CredentialSources::Basic { username, password } => Ok(Authentication::Basic {
+                username: username.read()?,
+                password: password.read()?,
+            }),
+            CredentialSources::Bearer { token } => Ok(Authentication::Bearer {
+                token: token.read()?,
+            }),
+        }
+    }
+    async fn read_async(&self) -> io::Result<Authentication> {
+        match self {
+            CredentialSources::Basic { username, password } => Ok(Authentication::Basic {
+                username: username.read_async().await?,
+                password: password.read_async().await?,
+            }),
+            CredentialSources::Bearer { token } => Ok(Authentication::Bearer {
+                token: token.read_async().await?,
+            }),
+        }
+    }
+}
+
+/// How requests to ClickHouse are authenticated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authentication {
+    /// Sent as the `X-ClickHouse-User` and `X-ClickHouse-Key` headers
+    Basic { username: String, password: String },
+    /// Sent as an `Authorization: Bearer` header, for ClickHouse Cloud or proxies that accept tokens
+    Bearer { token: String },
+}
+
+impl Default for Authentication {
+    fn default() -> Self {
+        Self::Basic {
+            username: String::new(),
+            password: String::new(),
+        }
+    }
+}
+
+impl Credentials {
+    /// Read a username and password, failing if any of the files cannot be read
+    pub fn basic(username: Secret, password: Secret) -> io::Result<Self> {
+        Self::new(CredentialSources::Basic { username, password })
+    }
+    /// Read a bearer token, failing if the file cannot be read
+    pub fn bearer(token: Secret) -> io::Result<Self> {
+        Self::new(CredentialSources::Bearer { token })
+    }
+    fn new(sources: CredentialSources) -> io::Result<Self> {
+        let current = sources.read()?;
+
+        Ok(Self {
+            sources,
+            current: Arc::new(RwLock::new(current)),
+        })
+    }
+    /// The current credentials
+    pub fn authentication(&self) -> Authentication {
+        self.current
+            .read()
+            .map(|current| current.to_owned())
+            .unwrap_or_default()
+    }
+    /// The current username, if authenticating with a username and password
+    pub fn username(&self) -> Option<String> {
+        match self.authentication() {
+            Authentication::Basic { username, .. } => Some(username),
+            Authentication::Bearer { .. } => None,
+        }
+    }
+    /// Whether any of the credentials are read from files, and may change when reloaded
+    pub fn is_reloadable(&self) -> bool {
+        match &self.sources {
+            CredentialSources::Basic { username, password } => {
+                username.is_file() || password.is_file()
+            }
+            CredentialSources::Bearer { token } => token.is_file(),
+        }
+    }
+    /// Re-read credentials from files. Returns whether the credentials changed.
+    /// If any file cannot be read, the current credentials are kept
+    pub async fn reload(&self) -> io::Result<bool> {
+        let reloaded = self.sources.read_async().await?;
+
+        let mut current = self
+            .current
+            .write()
+            .map_err(|_| io::Error::other("credentials lock poisoned"))?;
+
+        if *current == reloaded {
+            return Ok(false);
+        }
+
+        *current = reloaded;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    fn secret_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            env::temp_dir().join(format!("ndc-clickhouse-{name}-{}.txt", std::process::id()));
+        fs::write(&path, content).expect("Should write file");
+        path
+    }
+
+    fn basic(username: &str, password: &str) -> Authentication {
+        Authentication::Basic {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+
+    #[test]
+    fn ignore_trailing_newlines_in_files() {
+        let password = secret_file("trailing-newline", "secret\r\n");
+
+        let credentials = Credentials::basic(
+            Secret::Value("default".to_owned()),
+            Secret::File(password.to_owned()),
+        )
+        .expect("Should read credentials");
+
+        assert_eq!(credentials.authentication(), basic("default", "secret"));
+        assert!(credentials.is_reloadable());
+    }
+    #[test]
+    fn reject_unreadable_or_empty_files() {
+        let missing = env::temp_dir().join(format!(
+            "ndc-clickhouse-missing-token-{}.txt",
+            std::process::id()
+        ));
+        let empty = secret_file("empty-token", "\n");
+
+        let error = Credentials::bearer(Secret::File(missing)).expect_err("Should fail to read");
+        assert!(error.to_string().contains("unable to read"), "{error}");
+
+        let error = Credentials::bearer(Secret::File(empty)).expect_err("Should fail to read");
+        assert!(error.to_string().contains("is empty"), "{error}");
+    }
+    #[tokio::test]
+    async fn reload_rotated_files() {
+        let password = secret_file("rotated-password", "first");
+
+        let credentials = Credentials::basic(
+            Secret::Value("default".to_owned()),
+            Secret::File(password.to_owned()),
+        )
+        .expect("Should read credentials");
+        let clone = credentials.clone();
+
+        assert!(!credentials.reload().await.expect("Should reload"));
+
+        fs::write(&password, "second\n").expect("Should write file");
+
+        assert!(credentials.reload().await.expect("Should reload"));
+        assert_eq!(clone.authentication(), basic("default", "second"));
+    }
+    #[tokio::test]
+    async fn keep_credentials_when_reload_fails() {
+        let token = secret_file("reloaded-token", "first");
+
+        let credentials =
+            Credentials::bearer(Secret::File(token.to_owned())).expect("Should read credentials");
+        let expected = Authentication::Bearer {
+            token: "first".to_owned(),
+        };
+
+        fs::write(&token, "").expect("Should write file");
+        let error = credentials
+            .reload()
+            .await
+            .expect_err("Should fail to reload");
+        assert!(error.to_string().contains("is empty"), "{error}");
+        assert_eq!(credentials.authentication(), expected);
+
+        fs::remove_file(&token).expect("Should remove file");
+        let error = credentials
+            .reload()
+            .await
+            .expect_err("Should fail to reload");
+        assert!(error.to_string().contains("unable to read"), "{error}");
+        assert_eq!(credentials.authentication(), expected);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::config::{ConnectionConfig, LoadBalancing};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The ClickHouse endpoints we can send queries to.
+/// Endpoints that fail with a connection error are ejected for a cool-down period,
+/// during which they are only used if all other endpoints are ejected as well
+#[derive(Debug)]
+pub struct Endpoints {
+    endpoints: Vec<Endpoint>,
+    load_balancing: LoadBalancing,
+    cooldown: Duration,
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    url: String,
+    in_flight: AtomicUsize,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Endpoint {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until
+            .lock()
+            .map(|ejected_until| ejected_until.is_some_and(|until| until > now))
+            .unwrap_or(false)
+    }
+}
+
+impl Endpoints {
+    pub fn new(connection_config: &ConnectionConfig) -> Self {
+        Self {
+            endpoints: connection_config
+                .urls
+                .iter()
+                .map(|url| Endpoint {
+                    url: url.to_owned(),
+                    in_flight: AtomicUsize::new(0),
+                    ejected_until: Mutex::new(None),
+                })
+                .collect(),
+            load_balancing: connection_config.load_balancing,
+            cooldown: connection_config.endpoint_cooldown,
+            next: AtomicUsize::new(0),
+        }
+    }
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.endpoints.iter().map(|endpoint| endpoint.url.as_str())
+    }
+    /// Select an endpoint for a request. The request is counted as in flight until the returned guard is dropped
+    pub fn select(&self) -> EndpointGuard<'_> {
+        let now = Instant::now();
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+
+        let mut candidates: Vec<&Endpoint> = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| !endpoint.is_ejected(now))
+            .collect();
+
+        if candidates.is_empty() {
+            candidates = self.endpoints.iter().collect();
+        }
+
+        // rotate candidates so ties are spread evenly
+        let len = candidates.len();
+        let rotated = candidates
+            .iter()
+            .cycle()
+            .skip(offset % len.max(1))
+            .take(len);
+
+        let endpoint = match self.load_balancing {
+            LoadBalancing::RoundRobin => rotated.copied().next(),
+            LoadBalancing::LeastInFlight => rotated
+                .copied()
+                .min_by_key(|endpoint| endpoint.in_flight.load(Ordering::Relaxed)),
+        }
+        .expect("connection configuration should contain at least one url");
+
+        endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        EndpointGuard {
+            endpoint,
+            cooldown: self.cooldown,
+        }
+    }
+}
+
+pub struct EndpointGuard<'a> {
+    endpoint: &'a Endpoint,
+    cooldown: Duration,
+}
+
+impl EndpointGuard<'_> {
+    pub fn url(&self) -> &str {
+        &self.endpoint.url
+    }
+    /// Stop sending requests to this endpoint for the cool-down period
+    pub fn eject(&self) {
+        tracing::warn!(
+            url = self.endpoint.url.as_str(),
+            "Ejecting unhealthy ClickHouse endpoint for {}s",
+            self.cooldown.as_secs()
+        );
+
+        if let Ok(mut ejected_until) = self.endpoint.ejected_until.lock() {
+            *ejected_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+impl Drop for EndpointGuard<'_> {
+    fn drop(&mut self) {
+        self.endpoint.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{get_connection_configuration, ConfigurationEnvironment};
+    use std::{collections::HashMap, thread};
+
+    fn endpoints(load_balancing: LoadBalancing, cooldown: Duration) -> Endpoints {
+        let env = ConfigurationEnvironment::from_simulated_environment(HashMap::from([
+            (
+                "CLICKHOUSE_URL".to_owned(),
+                "http://a:8123,http://b:8123,http://c:8123".to_owned(),
+            ),
+            ("CLICKHOUSE_USERNAME".to_owned(), "default".to_owned()),
+            ("CLICKHOUSE_PASSWORD".to_owned(), "".to_owned()),
+        ]));
+        let mut connection_config =
+            get_connection_configuration(&env).expect("Should read connection configuration");
+        connection_config.load_balancing = load_balancing;
+        connection_config.endpoint_cooldown = cooldown;
+
+        Endpoints::new(&connection_config)
+    }
+
+    fn select_urls(endpoints: &Endpoints, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| endpoints.select().url().to_owned())
+            .collect()
+    }
+
+    fn in_flight(endpoints: &Endpoints) -> Vec<usize> {
+        endpoints
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.in_flight.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_rotates_through_endpoints() {
+        let endpoints = endpoints(LoadBalancing::RoundRobin, Duration::from_secs(30));
+
+        assert_eq!(
+            select_urls(&endpoints, 4),
+            [
+                "http://a:8123",
+                "http://b:8123",
+                "http://c:8123",
+                "http://a:8123"
+            ]
+        );
+    }
+    #[test]
+    fn skip_ejected_endpoints() {
+        let endpoints = endpoints(LoadBalancing::RoundRobin, Duration::from_secs(30));
+
+        let endpoint = endpoints.select();
+        assert_eq!(endpoint.url(), "http://a:8123");
+        endpoint.eject();
+        drop(endpoint);
+
+        assert!(select_urls(&endpoints, 6)
+            .iter()
+            .all(|url| url != "http://a:8123"));
+    }
+    #[test]
+    fn use_all_endpoints_when_every_endpoint_is_ejected() {
+        let endpoints = endpoints(LoadBalancing::RoundRobin, Duration::from_secs(30));
+
+        for _ in 0..3 {
+            endpoints.select().eject();
+        }
+
+        let mut urls = select_urls(&endpoints, 3);
+        urls.sort();
+
+        assert_eq!(urls, ["http://a:8123", "http://b:8123", "http://c:8123"]);
+    }
+    #[test]
+    fn restore_ejected_endpoints_after_cooldown() {
+        let endpoints = endpoints(LoadBalancing::RoundRobin, Duration::from_millis(50));
+
+        endpoints.select().eject();
+        assert!(select_urls(&endpoints, 6)
+            .iter()
+            .all(|url| url != "http://a:8123"));
+
+        thread::sleep(Duration::from_millis(60));
+
+        assert!(select_urls(&endpoints, 3)
+            .iter()
+            .any(|url| url == "http://a:8123"));
+    }
+    #[test]
+    fn least_in_flight_prefers_idle_endpoints() {
+        let endpoints = endpoints(LoadBalancing::LeastInFlight, Duration::from_secs(30));
+
+        let first = endpoints.select();
+        let second = endpoints.select();
+        let third = endpoints.select();
+        let mut urls = vec![first.url(), second.url(), third.url()];
+        urls.sort();
+        assert_eq!(urls, ["http://a:8123", "http://b:8123", "http://c:8123"]);
+
+        let url = second.url().to_owned();
+        drop(second);
+
+        assert_eq!(endpoints.select().url(), url);
+    }
+    #[test]
+    fn release_in_flight_requests_when_guard_is_dropped() {
+        let endpoints = endpoints(LoadBalancing::RoundRobin, Duration::from_secs(30));
+
+        let first = endpoints.select();
+        let second = endpoints.select();
+        assert_eq!(in_flight(&endpoints), [1, 1, 0]);
+
+        drop(first);
+        assert_eq!(in_flight(&endpoints), [0, 1, 0]);
+
+        second.eject();
+        drop(second);
+        assert_eq!(in_flight(&endpoints), [0, 0, 0]);
+    }
+}
@@ -3,5 +3,7 @@ pub mod clickhouse_parser;
 pub mod client;
 pub mod config;
 pub mod config_file;
+pub mod credentials;
+pub mod endpoints;
 pub mod format;
 pub mod schema;
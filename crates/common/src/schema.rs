@@ -6,110 +6,240 @@ use crate::{
     config::ServerConfig,
     config_file::{ParameterizedQueryExposedAs, PrimaryKey},
 };
-use ndc_models::{self as models, ObjectTypeName, ScalarTypeName};
+use ndc_models::{self as models, CollectionName, ObjectTypeName, ScalarTypeName};
 use std::collections::BTreeMap;
 use type_definition::{ClickHouseTypeDefinition, SchemaTypeDefinitions};
 pub mod binary_comparison_operator;
 pub mod single_column_aggregate_function;
 pub mod type_definition;
 
-pub fn schema_response(configuration: &ServerConfig) -> models::SchemaResponse {
-    let mut scalar_type_definitions: BTreeMap<ScalarTypeName, ndc_models::ScalarType> =
-        BTreeMap::new();
-    let mut object_type_definitions: BTreeMap<ObjectTypeName, ndc_models::ObjectType> =
-        BTreeMap::new();
-
-    for (type_name, table_type) in &configuration.table_types {
-        let mut fields = vec![];
-        for (column_alias, column_type) in &table_type.columns {
-            let type_definition = ClickHouseTypeDefinition::from_table_column(
-                column_type,
-                column_alias,
-                type_name,
-                &configuration.namespace_separator,
-            );
+/// A schema type name that is defined more than once, with conflicting definitions
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeNameCollision {
+    pub type_name: String,
+    /// path to the configuration node that produced the conflicting definition
+    pub node_path: Vec<String>,
+    /// path to the configuration node that first defined this type name
+    pub existing_node_path: Vec<String>,
+}
+
+/// Scalar and object types share a single namespace in the schema.
+/// Each definition is recorded along with the path to the configuration node that produced it
+#[derive(Default)]
+struct SchemaTypes {
+    scalars: BTreeMap<ScalarTypeName, (models::ScalarType, Vec<String>)>,
+    objects: BTreeMap<ObjectTypeName, (models::ObjectType, Vec<String>)>,
+    collisions: Vec<TypeNameCollision>,
+}
 
-            let SchemaTypeDefinitions { scalars, objects } = type_definition.type_definitions();
+impl SchemaTypes {
+    fn new(configuration: &ServerConfig) -> Self {
+        let mut types = Self::default();
 
-            for (name, definition) in objects {
-                object_type_definitions.insert(name, definition);
-            }
-            for (name, definition) in scalars {
-                // silently dropping duplicate scalar definitions
-                // this could be an issue if somehow an enum has the same name as a primitive scalar
-                // there is the potential for name collisions resulting in dropped enum defintions
-                scalar_type_definitions.insert(name, definition);
+        for (type_name, table_type) in &configuration.table_types {
+            // table types are keyed by the alias of the table or query that defines them
+            let section = if configuration
+                .tables
+                .contains_key(&CollectionName::from(type_name.to_string()))
+            {
+                "tables"
+            } else {
+                "queries"
+            };
+            let node_path = vec![section.to_string(), type_name.to_string()];
+
+            let mut fields = vec![];
+            for (column_alias, column_type) in &table_type.columns {
+                let type_definition = ClickHouseTypeDefinition::from_table_column(
+                    column_type,
+                    column_alias,
+                    type_name,
+                    &configuration.namespace_separator,
+                    &configuration.scalar_types,
+                );
+
+                types.insert(
+                    type_definition.type_definitions(),
+                    node_path
+                        .iter()
+                        .cloned()
+                        .chain([
+                            "return_type".to_string(),
+                            "columns".to_string(),
+                            column_alias.to_string(),
+                        ])
+                        .collect(),
+                );
+
+                fields.push((
+                    column_alias.to_owned(),
+                    models::ObjectField {
+                        description: None,
+                        r#type: type_definition.type_identifier(),
+                        arguments: BTreeMap::new(),
+                    },
+                ));
             }
 
-            fields.push((
-                column_alias.to_owned(),
-                models::ObjectField {
-                    description: None,
-                    r#type: type_definition.type_identifier(),
-                    arguments: BTreeMap::new(),
+            types.insert(
+                SchemaTypeDefinitions {
+                    scalars: vec![],
+                    objects: vec![(
+                        type_name.to_owned(),
+                        models::ObjectType {
+                            description: table_type.comment.to_owned(),
+                            fields: fields.into_iter().collect(),
+                        },
+                    )],
                 },
-            ));
+                node_path,
+            );
         }
 
-        object_type_definitions.insert(
-            type_name.to_owned(),
-            models::ObjectType {
-                description: table_type.comment.to_owned(),
-                fields: fields.into_iter().collect(),
-            },
-        );
-    }
-
-    for (table_alias, table_config) in &configuration.tables {
-        for (argument_name, argument_type) in &table_config.arguments {
-            let type_definition = ClickHouseTypeDefinition::from_query_argument(
-                argument_type,
-                argument_name.inner(),
-                table_alias.inner(),
-                &configuration.namespace_separator,
-            );
-            let SchemaTypeDefinitions { scalars, objects } = type_definition.type_definitions();
+        for (table_alias, table_config) in &configuration.tables {
+            for (argument_name, argument_type) in table_config
+                .arguments
+                .iter()
+                .map(|(name, r#type)| (name.to_owned(), r#type.to_owned()))
+                .chain(table_config.sample_arguments())
+                .chain(configuration.request_arguments())
+            {
+                let type_definition = ClickHouseTypeDefinition::from_query_argument(
+                    &argument_type,
+                    argument_name.inner(),
+                    table_alias.inner(),
+                    &configuration.namespace_separator,
+                    &configuration.scalar_types,
+                );
 
-            for (name, definition) in objects {
-                object_type_definitions.insert(name, definition);
-            }
-            for (name, definition) in scalars {
-                // silently dropping duplicate scalar definitions
-                // this could be an issue if somehow an enum has the same name as a primitive scalar
-                // there is the potential for name collisions resulting in dropped enum defintions
-                scalar_type_definitions.insert(name, definition);
+                types.insert(
+                    type_definition.type_definitions(),
+                    vec![
+                        "tables".to_string(),
+                        table_alias.to_string(),
+                        "arguments".to_string(),
+                        argument_name.to_string(),
+                    ],
+                );
             }
         }
-    }
 
-    for (query_alias, query_config) in &configuration.queries {
-        for element in &query_config.query.elements {
-            if let ParameterizedQueryElement::Parameter(Parameter { name, r#type }) = element {
-                let data_type = match r#type {
-                    ParameterType::Identifier => &ClickHouseDataType::String,
-                    ParameterType::DataType(t) => t,
-                };
+        for (query_alias, query_config) in &configuration.queries {
+            for element in &query_config.query.elements {
+                if let ParameterizedQueryElement::Parameter(Parameter { name, r#type }) = element {
+                    let data_type = match r#type {
+                        ParameterType::Identifier => &ClickHouseDataType::String,
+                        ParameterType::DataType(t) => t,
+                    };
+                    let type_definition = ClickHouseTypeDefinition::from_query_argument(
+                        data_type,
+                        name.value(),
+                        query_alias.inner(),
+                        &configuration.namespace_separator,
+                        &configuration.scalar_types,
+                    );
+
+                    types.insert(
+                        type_definition.type_definitions(),
+                        vec![
+                            "queries".to_string(),
+                            query_alias.to_string(),
+                            "file".to_string(),
+                        ],
+                    );
+                }
+            }
+
+            for (argument_name, argument_type) in configuration.request_arguments() {
                 let type_definition = ClickHouseTypeDefinition::from_query_argument(
-                    data_type,
-                    name.value(),
+                    &argument_type,
+                    argument_name.inner(),
                     query_alias.inner(),
                     &configuration.namespace_separator,
+                    &configuration.scalar_types,
+                );
+
+                types.insert(
+                    type_definition.type_definitions(),
+                    vec!["queries".to_string(), query_alias.to_string()],
                 );
+            }
+        }
 
-                let SchemaTypeDefinitions { scalars, objects } = type_definition.type_definitions();
+        types
+    }
+    /// record type definitions, unless a definition with the same name already exists.
+    /// Identical definitions are expected, as types are shared between columns. Conflicting definitions are recorded as collisions
+    fn insert(&mut self, definitions: SchemaTypeDefinitions, node_path: Vec<String>) {
+        let SchemaTypeDefinitions { scalars, objects } = definitions;
 
-                for (name, definition) in objects {
-                    object_type_definitions.insert(name, definition);
+        for (name, definition) in scalars {
+            let existing_node_path = match (
+                self.scalars.get(&name),
+                self.objects.get(&ObjectTypeName::from(name.to_string())),
+            ) {
+                (Some((existing, _)), _) if existing == &definition => continue,
+                (Some((_, existing_node_path)), _) | (None, Some((_, existing_node_path))) => {
+                    existing_node_path.to_owned()
                 }
-                for (name, definition) in scalars {
-                    // silently dropping duplicate scalar definitions
-                    // this could be an issue if somehow an enum has the same name as a primitive scalar
-                    // there is the potential for name collisions resulting in dropped enum defintions
-                    scalar_type_definitions.insert(name, definition);
+                (None, None) => {
+                    self.scalars
+                        .insert(name, (definition, node_path.to_owned()));
+                    continue;
                 }
-            }
+            };
+
+            self.collisions.push(TypeNameCollision {
+                type_name: name.to_string(),
+                node_path: node_path.to_owned(),
+                existing_node_path,
+            });
+        }
+
+        for (name, definition) in objects {
+            let existing_node_path = match (
+                self.objects.get(&name),
+                self.scalars.get(&ScalarTypeName::from(name.to_string())),
+            ) {
+                (Some((existing, _)), _) if existing == &definition => continue,
+                (Some((_, existing_node_path)), _) | (None, Some((_, existing_node_path))) => {
+                    existing_node_path.to_owned()
+                }
+                (None, None) => {
+                    self.objects
+                        .insert(name, (definition, node_path.to_owned()));
+                    continue;
+                }
+            };
+
+            self.collisions.push(TypeNameCollision {
+                type_name: name.to_string(),
+                node_path: node_path.to_owned(),
+                existing_node_path,
+            });
         }
     }
+}
+
+/// Check that no two distinct scalar or object types share a name.
+/// Returns the first collision found, if any
+pub fn validate_type_names(configuration: &ServerConfig) -> Result<(), TypeNameCollision> {
+    match SchemaTypes::new(configuration)
+        .collisions
+        .into_iter()
+        .next()
+    {
+        Some(collision) => Err(collision),
+        None => Ok(()),
+    }
+}
+
+pub fn schema_response(configuration: &ServerConfig) -> models::SchemaResponse {
+    // configuration is validated on load, so we don't expect collisions here.
+    // should any occur, the first definition is kept
+    let SchemaTypes {
+        scalars, objects, ..
+    } = SchemaTypes::new(configuration);
 
     let table_collections = configuration
         .tables
@@ -120,15 +250,19 @@ pub fn schema_response(configuration: &ServerConfig) -> models::SchemaResponse {
             arguments: table_config
                 .arguments
                 .iter()
+                .map(|(name, r#type)| (name.to_owned(), r#type.to_owned()))
+                .chain(table_config.sample_arguments())
+                .chain(configuration.request_arguments())
                 .map(|(argument_name, argument_type)| {
                     let type_definition = ClickHouseTypeDefinition::from_query_argument(
-                        argument_type,
+                        &argument_type,
                         argument_name.inner(),
                         table_alias.inner(),
                         &configuration.namespace_separator,
+                        &configuration.scalar_types,
                     );
                     (
-                        argument_name.to_owned(),
+                        argument_name,
                         models::ArgumentInfo {
                             description: None,
                             argument_type: type_definition.type_identifier(),
@@ -176,6 +310,7 @@ pub fn schema_response(configuration: &ServerConfig) -> models::SchemaResponse {
                             name.value(),
                             query_alias.inner(),
                             &configuration.namespace_separator,
+                            &configuration.scalar_types,
                         );
 
                         Some((
@@ -187,6 +322,25 @@ pub fn schema_response(configuration: &ServerConfig) -> models::SchemaResponse {
                         ))
                     }
                 })
+                .chain(configuration.request_arguments().into_iter().map(
+                    |(argument_name, argument_type)| {
+                        let type_definition = ClickHouseTypeDefinition::from_query_argument(
+                            &argument_type,
+                            argument_name.inner(),
+                            query_alias.inner(),
+                            &configuration.namespace_separator,
+                            &configuration.scalar_types,
+                        );
+
+                        (
+                            argument_name,
+                            models::ArgumentInfo {
+                                description: None,
+                                argument_type: type_definition.type_identifier(),
+                            },
+                        )
+                    },
+                ))
                 .collect();
 
             models::CollectionInfo {
@@ -202,10 +356,14 @@ pub fn schema_response(configuration: &ServerConfig) -> models::SchemaResponse {
     let collections = table_collections.chain(query_collections).collect();
 
     models::SchemaResponse {
-        scalar_types: scalar_type_definitions,
-        // converting vector to map drops any duplicate definitions
-        // this could be an issue if there are name collisions
-        object_types: object_type_definitions,
+        scalar_types: scalars
+            .into_iter()
+            .map(|(name, (definition, _))| (name, definition))
+            .collect(),
+        object_types: objects
+            .into_iter()
+            .map(|(name, (definition, _))| (name, definition))
+            .collect(),
         collections,
         functions: vec![],
         procedures: vec![],
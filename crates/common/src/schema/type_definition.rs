@@ -1,4 +1,7 @@
-use crate::clickhouse_parser::datatype::{ClickHouseDataType, Identifier, SingleQuotedString};
+use crate::{
+    clickhouse_parser::datatype::{ClickHouseDataType, Identifier, SingleQuotedString},
+    config_file::ScalarTypesConfig,
+};
 use indexmap::IndexMap;
 use ndc_models::{self as models, FieldName, ObjectTypeName, ScalarTypeName};
 use std::{collections::BTreeMap, iter};
@@ -35,15 +38,33 @@ impl<'a> NameSpace<'a> {
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct ClickHouseScalar(ClickHouseDataType);
+pub struct ClickHouseScalar {
+    /// the canonical data type, which determines the scalar type name and definition in the schema
+    schema_type: ClickHouseDataType,
+    /// the exact data type, used for casting
+    data_type: ClickHouseDataType,
+}
 
 impl ClickHouseScalar {
+    fn new(data_type: &ClickHouseDataType, options: &ScalarTypesConfig) -> Self {
+        Self {
+            schema_type: canonical_type(data_type, options),
+            data_type: data_type.to_owned(),
+        }
+    }
+    /// for types that are already canonical, such as aggregate function result types
+    fn from_canonical(data_type: ClickHouseDataType) -> Self {
+        Self {
+            schema_type: data_type.clone(),
+            data_type,
+        }
+    }
     fn type_name(&self) -> ScalarTypeName {
-        self.0.to_string().into()
+        self.schema_type.to_string().into()
     }
     fn cast_type(&self) -> ClickHouseDataType {
         // todo: recusively map large number types to string here
-        self.0.clone()
+        self.data_type.clone()
     }
     fn type_definition(&self) -> models::ScalarType {
         models::ScalarType {
@@ -106,9 +127,9 @@ impl ClickHouseScalar {
     }
     fn json_representation(&self) -> Option<models::TypeRepresentation> {
         use models::TypeRepresentation as Rep;
-        match &self.0 {
+        match &self.schema_type {
             ClickHouseDataType::Bool => Some(Rep::Boolean),
-            ClickHouseDataType::String => Some(Rep::String),
+            ClickHouseDataType::String | ClickHouseDataType::FixedString(_) => Some(Rep::String),
             ClickHouseDataType::UInt8 => Some(Rep::Int16), // Unsigned int8 fits into signed int16
             ClickHouseDataType::UInt16 => Some(Rep::Int32), // Unsigned int16 fits into signed int32
             ClickHouseDataType::UInt32 => Some(Rep::Int64), // Unsigned int32 fits into signed int64
@@ -128,10 +149,10 @@ impl ClickHouseScalar {
             ClickHouseDataType::Decimal64 { .. } => Some(Rep::String),
             ClickHouseDataType::Decimal128 { .. } => Some(Rep::String),
             ClickHouseDataType::Decimal256 { .. } => Some(Rep::String),
-            ClickHouseDataType::Date => Some(Rep::String),
-            ClickHouseDataType::Date32 => Some(Rep::String),
-            ClickHouseDataType::DateTime { .. } => Some(Rep::String),
-            ClickHouseDataType::DateTime64 { .. } => Some(Rep::String),
+            ClickHouseDataType::Date => Some(Rep::Date),
+            ClickHouseDataType::Date32 => Some(Rep::Date),
+            ClickHouseDataType::DateTime { .. } => Some(Rep::TimestampTZ),
+            ClickHouseDataType::DateTime64 { .. } => Some(Rep::TimestampTZ),
             ClickHouseDataType::Uuid => Some(Rep::String),
             ClickHouseDataType::IPv4 => Some(Rep::String),
             ClickHouseDataType::IPv6 => Some(Rep::String),
@@ -151,9 +172,9 @@ impl ClickHouseScalar {
     ) -> Vec<(ClickHouseSingleColumnAggregateFunction, ClickHouseDataType)> {
         use ClickHouseSingleColumnAggregateFunction as AF;
 
-        match self.0 {
+        match self.schema_type {
             ClickHouseDataType::Bool => vec![],
-            ClickHouseDataType::String => vec![],
+            ClickHouseDataType::String | ClickHouseDataType::FixedString(_) => vec![],
             ClickHouseDataType::UInt8 => vec![
                 (AF::Max, ClickHouseDataType::UInt8),
                 (AF::Min, ClickHouseDataType::UInt8),
@@ -295,9 +316,9 @@ impl ClickHouseScalar {
                 (AF::VarSamp, ClickHouseDataType::Float64),
             ],
             ClickHouseDataType::Decimal { .. } => vec![
-                (AF::Max, self.0.to_owned()),
-                (AF::Min, self.0.to_owned()),
-                (AF::Sum, self.0.to_owned()),
+                (AF::Max, self.schema_type.to_owned()),
+                (AF::Min, self.schema_type.to_owned()),
+                (AF::Sum, self.schema_type.to_owned()),
                 (AF::Avg, ClickHouseDataType::Float64),
                 (AF::StddevPop, ClickHouseDataType::Float64),
                 (AF::StddevSamp, ClickHouseDataType::Float64),
@@ -305,9 +326,9 @@ impl ClickHouseScalar {
                 (AF::VarSamp, ClickHouseDataType::Float64),
             ],
             ClickHouseDataType::Decimal32 { .. } => vec![
-                (AF::Max, self.0.to_owned()),
-                (AF::Min, self.0.to_owned()),
-                (AF::Sum, self.0.to_owned()),
+                (AF::Max, self.schema_type.to_owned()),
+                (AF::Min, self.schema_type.to_owned()),
+                (AF::Sum, self.schema_type.to_owned()),
                 (AF::Avg, ClickHouseDataType::Float64),
                 (AF::StddevPop, ClickHouseDataType::Float64),
                 (AF::StddevSamp, ClickHouseDataType::Float64),
@@ -315,9 +336,9 @@ impl ClickHouseScalar {
                 (AF::VarSamp, ClickHouseDataType::Float64),
             ],
             ClickHouseDataType::Decimal64 { .. } => vec![
-                (AF::Max, self.0.to_owned()),
-                (AF::Min, self.0.to_owned()),
-                (AF::Sum, self.0.to_owned()),
+                (AF::Max, self.schema_type.to_owned()),
+                (AF::Min, self.schema_type.to_owned()),
+                (AF::Sum, self.schema_type.to_owned()),
                 (AF::Avg, ClickHouseDataType::Float64),
                 (AF::StddevPop, ClickHouseDataType::Float64),
                 (AF::StddevSamp, ClickHouseDataType::Float64),
@@ -325,9 +346,9 @@ impl ClickHouseScalar {
                 (AF::VarSamp, ClickHouseDataType::Float64),
             ],
             ClickHouseDataType::Decimal128 { .. } => vec![
-                (AF::Max, self.0.to_owned()),
-                (AF::Min, self.0.to_owned()),
-                (AF::Sum, self.0.to_owned()),
+                (AF::Max, self.schema_type.to_owned()),
+                (AF::Min, self.schema_type.to_owned()),
+                (AF::Sum, self.schema_type.to_owned()),
                 (AF::Avg, ClickHouseDataType::Float64),
                 (AF::StddevPop, ClickHouseDataType::Float64),
                 (AF::StddevSamp, ClickHouseDataType::Float64),
@@ -335,9 +356,9 @@ impl ClickHouseScalar {
                 (AF::VarSamp, ClickHouseDataType::Float64),
             ],
             ClickHouseDataType::Decimal256 { .. } => vec![
-                (AF::Max, self.0.to_owned()),
-                (AF::Min, self.0.to_owned()),
-                (AF::Sum, self.0.to_owned()),
+                (AF::Max, self.schema_type.to_owned()),
+                (AF::Min, self.schema_type.to_owned()),
+                (AF::Sum, self.schema_type.to_owned()),
                 (AF::Avg, ClickHouseDataType::Float64),
                 (AF::StddevPop, ClickHouseDataType::Float64),
                 (AF::StddevSamp, ClickHouseDataType::Float64),
@@ -353,10 +374,16 @@ impl ClickHouseScalar {
                 (AF::Min, ClickHouseDataType::Date32),
             ],
             ClickHouseDataType::DateTime { .. } => {
-                vec![(AF::Max, self.0.to_owned()), (AF::Min, self.0.to_owned())]
+                vec![
+                    (AF::Max, self.schema_type.to_owned()),
+                    (AF::Min, self.schema_type.to_owned()),
+                ]
             }
             ClickHouseDataType::DateTime64 { .. } => {
-                vec![(AF::Max, self.0.to_owned()), (AF::Min, self.0.to_owned())]
+                vec![
+                    (AF::Max, self.schema_type.to_owned()),
+                    (AF::Min, self.schema_type.to_owned()),
+                ]
             }
             _ => vec![],
         }
@@ -368,9 +395,9 @@ impl ClickHouseScalar {
         let ordering_operators = vec![BC::Gt, BC::Lt, BC::GtEq, BC::LtEq];
         let string_operators = vec![BC::Like, BC::NotLike, BC::ILike, BC::NotILike, BC::Match];
 
-        match self.0 {
+        match self.schema_type {
             ClickHouseDataType::Bool => equality_operators,
-            ClickHouseDataType::String => {
+            ClickHouseDataType::String | ClickHouseDataType::FixedString(_) => {
                 [equality_operators, ordering_operators, string_operators].concat()
             }
             ClickHouseDataType::UInt8
@@ -431,54 +458,63 @@ impl ClickHouseTypeDefinition {
         column_alias: &FieldName,
         return_type: &ObjectTypeName,
         separator: &str,
+        options: &ScalarTypesConfig,
     ) -> Self {
         let namespace = NameSpace::new(
             vec![return_type.inner().inner(), column_alias.inner()],
             separator,
         );
-        Self::new(data_type, &namespace)
+        Self::new(data_type, &namespace, options)
     }
     pub fn from_query_return_type(
         data_type: &ClickHouseDataType,
         field_alias: &str,
         query_alias: &str,
         separator: &str,
+        options: &ScalarTypesConfig,
     ) -> Self {
         let namespace = NameSpace::new(vec![query_alias, field_alias], separator);
-        Self::new(data_type, &namespace)
+        Self::new(data_type, &namespace, options)
     }
     pub fn from_query_argument(
         data_type: &ClickHouseDataType,
         argument_alias: &str,
         query_alias: &str,
         separator: &str,
+        options: &ScalarTypesConfig,
     ) -> Self {
         let namespace = NameSpace::new(vec![query_alias, "_arg", argument_alias], separator);
-        Self::new(data_type, &namespace)
+        Self::new(data_type, &namespace, options)
     }
-    fn new(data_type: &ClickHouseDataType, namespace: &NameSpace) -> Self {
+    fn new(
+        data_type: &ClickHouseDataType,
+        namespace: &NameSpace,
+        options: &ScalarTypesConfig,
+    ) -> Self {
         match data_type {
             ClickHouseDataType::Nullable(inner) => Self::Nullable {
-                inner: Box::new(Self::new(inner, namespace)),
+                inner: Box::new(Self::new(inner, namespace, options)),
             },
-            ClickHouseDataType::String | ClickHouseDataType::FixedString(_) => {
-                Self::Scalar(ClickHouseScalar(ClickHouseDataType::String))
+            // LowCardinality(Nullable(T)) must become a nullable type, we cast to Nullable(T) in that case
+            ClickHouseDataType::LowCardinality(inner)
+                if matches!(**inner, ClickHouseDataType::Nullable(_)) =>
+            {
+                Self::new(inner, namespace, options)
             }
-            ClickHouseDataType::LowCardinality(inner) => Self::new(inner, namespace),
             ClickHouseDataType::Nested(entries) => {
                 let mut fields = IndexMap::new();
 
                 for (name, field_data_type) in entries {
                     let field_namespace = namespace.child(name.value());
 
-                    let field_definition = Self::new(field_data_type, &field_namespace);
+                    let field_definition = Self::new(field_data_type, &field_namespace, options);
 
                     if fields
                         .insert(name.value().to_owned().into(), field_definition)
                         .is_some()
                     {
                         // on duplicate field names, fall back to unknown type
-                        return Self::Scalar(ClickHouseScalar(data_type.to_owned()));
+                        return Self::Scalar(ClickHouseScalar::new(data_type, options));
                     }
                 }
 
@@ -490,7 +526,7 @@ impl ClickHouseTypeDefinition {
                 }
             }
             ClickHouseDataType::Array(element) => Self::Array {
-                element_type: Box::new(Self::new(element, namespace)),
+                element_type: Box::new(Self::new(element, namespace, options)),
             },
             ClickHouseDataType::Tuple(entries) => {
                 let mut fields = IndexMap::new();
@@ -500,19 +536,19 @@ impl ClickHouseTypeDefinition {
                         name.value()
                     } else {
                         // anonymous tuples treated as scalar types
-                        return Self::Scalar(ClickHouseScalar(data_type.to_owned()));
+                        return Self::Scalar(ClickHouseScalar::new(data_type, options));
                     };
 
                     let field_namespace = namespace.child(field_name);
 
-                    let field_definition = Self::new(field_data_type, &field_namespace);
+                    let field_definition = Self::new(field_data_type, &field_namespace, options);
 
                     if fields
                         .insert(field_name.to_owned().into(), field_definition)
                         .is_some()
                     {
                         // on duplicate field names, fall back to unknown type
-                        return Self::Scalar(ClickHouseScalar(data_type.to_owned()));
+                        return Self::Scalar(ClickHouseScalar::new(data_type, options));
                     }
                 }
 
@@ -526,29 +562,33 @@ impl ClickHouseTypeDefinition {
                 arguments,
             } => {
                 if let (Some(data_type), 1) = (arguments.first(), arguments.len()) {
-                    Self::new(data_type, namespace)
+                    Self::new(data_type, namespace, options)
                 } else {
-                    Self::Scalar(ClickHouseScalar(data_type.to_owned()))
+                    Self::Scalar(ClickHouseScalar::new(data_type, options))
                 }
             }
             ClickHouseDataType::AggregateFunction {
                 function,
                 arguments,
             } => {
+                // states of known aggregate functions are exposed as their finalized result type.
+                // for other functions, we fall back to the argument type
                 let arg_len = arguments.len();
                 let first = arguments.first();
 
-                if let (Some(data_type), 1) = (first, arg_len) {
-                    Self::new(data_type, namespace)
+                if let Some(finalized_type) = finalized_aggregate_type(data_type) {
+                    Self::new(&finalized_type, namespace, options)
+                } else if let (Some(data_type), 1) = (first, arg_len) {
+                    Self::new(data_type, namespace, options)
                 } else if let (Some(data_type), 2, "anyIf") =
                     (first, arg_len, function.name.value())
                 {
-                    Self::new(data_type, namespace)
+                    Self::new(data_type, namespace, options)
                 } else {
-                    Self::Scalar(ClickHouseScalar(data_type.to_owned()))
+                    Self::Scalar(ClickHouseScalar::new(data_type, options))
                 }
             }
-            _ => Self::Scalar(ClickHouseScalar(data_type.to_owned())),
+            _ => Self::Scalar(ClickHouseScalar::new(data_type, options)),
         }
     }
     pub fn type_identifier(&self) -> models::Type {
@@ -606,7 +646,7 @@ impl ClickHouseTypeDefinition {
                     scalars: &mut IndexMap<ScalarTypeName, models::ScalarType>,
                 ) {
                     for (_, return_type) in aggregate_functions {
-                        let return_type = ClickHouseScalar(return_type);
+                        let return_type = ClickHouseScalar::from_canonical(return_type);
                         if !scalars.contains_key(&return_type.type_name()) {
                             scalars.insert(return_type.type_name(), return_type.type_definition());
                             get_dependencies(return_type.aggregate_functions(), scalars);
@@ -693,6 +733,109 @@ impl ClickHouseTypeDefinition {
     }
 }
 
+/// The result type of finalizing an AggregateFunction state, eg. using `finalizeAggregation` or the `-Merge` combinator.
+/// Returns None if the data type is not an AggregateFunction, or the result type is unknown
+pub fn finalized_aggregate_type(data_type: &ClickHouseDataType) -> Option<ClickHouseDataType> {
+    match data_type {
+        ClickHouseDataType::AggregateFunction {
+            function,
+            arguments,
+        } => aggregate_result_type(function.name.value(), arguments),
+        _ => None,
+    }
+}
+
+fn aggregate_result_type(
+    function_name: &str,
+    arguments: &[ClickHouseDataType],
+) -> Option<ClickHouseDataType> {
+    use ClickHouseDataType as DT;
+
+    match (function_name, arguments) {
+        (
+            "count" | "uniq" | "uniqExact" | "uniqCombined" | "uniqCombined64" | "uniqHLL12"
+            | "uniqTheta",
+            _,
+        ) => Some(DT::UInt64),
+        ("sum", [argument]) => match argument {
+            DT::UInt8 | DT::UInt16 | DT::UInt32 | DT::UInt64 => Some(DT::UInt64),
+            DT::Int8 | DT::Int16 | DT::Int32 | DT::Int64 => Some(DT::Int64),
+            DT::Float32 | DT::Float64 => Some(DT::Float64),
+            DT::UInt128 | DT::UInt256 | DT::Int128 | DT::Int256 => Some(argument.to_owned()),
+            DT::Decimal { scale, .. }
+            | DT::Decimal32 { scale }
+            | DT::Decimal64 { scale }
+            | DT::Decimal128 { scale } => Some(DT::Decimal128 { scale: *scale }),
+            DT::Decimal256 { scale } => Some(DT::Decimal256 { scale: *scale }),
+            _ => None,
+        },
+        (
+            "avg",
+            [DT::UInt8
+            | DT::UInt16
+            | DT::UInt32
+            | DT::UInt64
+            | DT::UInt128
+            | DT::UInt256
+            | DT::Int8
+            | DT::Int16
+            | DT::Int32
+            | DT::Int64
+            | DT::Int128
+            | DT::Int256
+            | DT::Float32
+            | DT::Float64],
+        ) => Some(DT::Float64),
+        ("min" | "max" | "any" | "anyLast" | "anyHeavy", [argument]) => Some(argument.to_owned()),
+        ("argMin" | "argMax", [argument, _]) => Some(argument.to_owned()),
+        ("groupArray" | "groupUniqArray", [argument]) => {
+            Some(DT::Array(Box::new(argument.to_owned())))
+        }
+        // the -If combinator adds a trailing condition argument, but does not change the result type
+        (function_name, [arguments @ .., DT::UInt8]) if function_name.ends_with("If") => {
+            aggregate_result_type(&function_name[..function_name.len() - 2], arguments)
+        }
+        _ => None,
+    }
+}
+
+/// The data type used to name and define a scalar type in the schema.
+/// LowCardinality wrappers are removed, and FixedString is optionally merged into String
+fn canonical_type(
+    data_type: &ClickHouseDataType,
+    options: &ScalarTypesConfig,
+) -> ClickHouseDataType {
+    match data_type {
+        ClickHouseDataType::LowCardinality(inner) => canonical_type(inner, options),
+        ClickHouseDataType::FixedString(_) if options.merge_fixed_string => {
+            ClickHouseDataType::String
+        }
+        ClickHouseDataType::Nullable(inner) => {
+            ClickHouseDataType::Nullable(Box::new(canonical_type(inner, options)))
+        }
+        ClickHouseDataType::Array(element) => {
+            ClickHouseDataType::Array(Box::new(canonical_type(element, options)))
+        }
+        ClickHouseDataType::Map { key, value } => ClickHouseDataType::Map {
+            key: Box::new(canonical_type(key, options)),
+            value: Box::new(canonical_type(value, options)),
+        },
+        ClickHouseDataType::Tuple(elements) => ClickHouseDataType::Tuple(
+            elements
+                .iter()
+                .map(|(name, element)| (name.to_owned(), canonical_type(element, options)))
+                .collect(),
+        ),
+        ClickHouseDataType::Nested(elements) => ClickHouseDataType::Nested(
+            elements
+                .iter()
+                .map(|(name, element)| (name.to_owned(), canonical_type(element, options)))
+                .collect(),
+        ),
+        _ => data_type.to_owned(),
+    }
+}
+
 pub struct SchemaTypeDefinitions {
     pub scalars: Vec<(ScalarTypeName, models::ScalarType)>,
     pub objects: Vec<(ObjectTypeName, models::ObjectType)>,
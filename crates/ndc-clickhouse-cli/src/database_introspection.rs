@@ -1,9 +1,12 @@
 use common::{
-    client::{execute_json_query, get_http_client},
+    clickhouse_parser::datatype::{ClickHouseDataType, Identifier},
+    client::{execute_json_query, get_http_client, ClickHouseError},
     config::ConnectionConfig,
     config_file::MaybeClickhouseDataType,
+    endpoints::Endpoints,
 };
 use serde::Deserialize;
+use std::collections::HashMap;
 
 #[derive(Debug, Deserialize)]
 pub struct TableInfo {
@@ -14,7 +17,10 @@ pub struct TableInfo {
     pub table_comment: Option<String>,
     #[allow(dead_code)]
     pub table_type: TableType,
+    /// The table engine, eg. "ReplacingMergeTree"
+    pub engine: String,
     pub primary_key: Option<String>,
+    pub sampling_key: Option<String>,
     pub view_definition: String,
     pub columns: Vec<ColumnInfo>,
 }
@@ -42,9 +48,202 @@ pub enum TableType {
 
 pub async fn introspect_database(
     connection_config: &ConnectionConfig,
-) -> Result<Vec<TableInfo>, reqwest::Error> {
+) -> Result<Vec<TableInfo>, ClickHouseError> {
     let introspection_sql = include_str!("./database_introspection.sql");
     let client = get_http_client(connection_config)?;
-    execute_json_query::<Vec<TableInfo>>(&client, connection_config, introspection_sql, &vec![])
-        .await
+    let endpoints = Endpoints::new(connection_config);
+    let tables = execute_json_query::<Vec<TableInfo>>(
+        &client,
+        connection_config,
+        &endpoints,
+        introspection_sql,
+        &vec![],
+    )
+    .await?;
+
+    Ok(tables
+        .into_iter()
+        .map(|table| TableInfo {
+            columns: group_nested_columns(table.columns),
+            ..table
+        })
+        .collect())
+}
+
+/// With `flatten_nested` enabled (the default), Nested columns are reported as separate `column.field` array columns.
+/// Group those back into a single Nested column, so they are exposed as an array of objects.
+/// Columns are only grouped if all columns sharing the prefix are arrays, and no column is named after the prefix itself.
+/// Groups keep the position of their first column, so the source column order is preserved
+fn group_nested_columns(columns: Vec<ColumnInfo>) -> Vec<ColumnInfo> {
+    let mut groups: Vec<(String, Vec<ColumnInfo>)> = vec![];
+    let mut group_positions: HashMap<String, usize> = HashMap::new();
+
+    for column in columns {
+        let group_name = match column.column_name.split_once('.') {
+            Some((prefix, _)) => prefix.to_owned(),
+            None => column.column_name.to_owned(),
+        };
+        match group_positions.get(&group_name) {
+            Some(position) => groups[*position].1.push(column),
+            None => {
+                group_positions.insert(group_name.to_owned(), groups.len());
+                groups.push((group_name, vec![column]));
+            }
+        }
+    }
+
+    groups
+        .into_iter()
+        .flat_map(|(group_name, columns)| {
+            let mut elements = vec![];
+
+            for column in &columns {
+                match (
+                    column.column_name.strip_prefix(&format!("{group_name}.")),
+                    &column.data_type,
+                ) {
+                    (
+                        Some(field_name),
+                        MaybeClickhouseDataType::Valid(ClickHouseDataType::Array(element_type)),
+                    ) => elements.push((
+                        Identifier::DoubleQuoted(field_name.to_owned()),
+                        (**element_type).to_owned(),
+                    )),
+                    _ => return columns,
+                }
+            }
+
+            vec![ColumnInfo {
+                column_name: group_name,
+                data_type: MaybeClickhouseDataType::Valid(ClickHouseDataType::Nested(elements)),
+                is_nullable: false,
+                is_in_primary_key: false,
+            }]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn data_type(data_type: &str) -> ClickHouseDataType {
+        ClickHouseDataType::from_str(data_type).expect("Should parse data type")
+    }
+
+    fn column(column_name: &str, column_type: &str) -> ColumnInfo {
+        ColumnInfo {
+            column_name: column_name.to_owned(),
+            data_type: MaybeClickhouseDataType::Valid(data_type(column_type)),
+            is_nullable: false,
+            is_in_primary_key: false,
+        }
+    }
+
+    fn nested(fields: &[(&str, &str)]) -> MaybeClickhouseDataType {
+        MaybeClickhouseDataType::Valid(ClickHouseDataType::Nested(
+            fields
+                .iter()
+                .map(|(field_name, field_type)| {
+                    (
+                        Identifier::DoubleQuoted((*field_name).to_owned()),
+                        data_type(field_type),
+                    )
+                })
+                .collect(),
+        ))
+    }
+
+    fn columns(columns: &[ColumnInfo]) -> Vec<(&str, &MaybeClickhouseDataType)> {
+        columns
+            .iter()
+            .map(|column| (column.column_name.as_str(), &column.data_type))
+            .collect()
+    }
+
+    #[test]
+    fn group_flattened_nested_columns() {
+        let grouped = group_nested_columns(vec![
+            column("Id", "UInt32"),
+            column("Tags.Name", "Array(String)"),
+            column("Name", "String"),
+            column("Tags.Count", "Array(Nullable(UInt32))"),
+        ]);
+
+        assert_eq!(
+            columns(&grouped),
+            [
+                ("Id", &MaybeClickhouseDataType::Valid(data_type("UInt32"))),
+                (
+                    "Tags",
+                    &nested(&[("Name", "String"), ("Count", "Nullable(UInt32)")])
+                ),
+                ("Name", &MaybeClickhouseDataType::Valid(data_type("String"))),
+            ]
+        );
+    }
+    #[test]
+    fn do_not_group_columns_named_after_prefix() {
+        let grouped = group_nested_columns(vec![
+            column("Tags", "String"),
+            column("Tags.Name", "Array(String)"),
+        ]);
+
+        assert_eq!(
+            columns(&grouped),
+            [
+                ("Tags", &MaybeClickhouseDataType::Valid(data_type("String"))),
+                (
+                    "Tags.Name",
+                    &MaybeClickhouseDataType::Valid(data_type("Array(String)"))
+                ),
+            ]
+        );
+    }
+    #[test]
+    fn do_not_group_columns_that_are_not_arrays() {
+        let grouped = group_nested_columns(vec![
+            column("Point.X", "Float64"),
+            column("Point.Y", "Array(Float64)"),
+        ]);
+
+        assert_eq!(
+            columns(&grouped),
+            [
+                (
+                    "Point.X",
+                    &MaybeClickhouseDataType::Valid(data_type("Float64"))
+                ),
+                (
+                    "Point.Y",
+                    &MaybeClickhouseDataType::Valid(data_type("Array(Float64)"))
+                ),
+            ]
+        );
+    }
+    #[test]
+    fn do_not_group_columns_with_unknown_types() {
+        let grouped = group_nested_columns(vec![
+            column("Tags.Name", "Array(String)"),
+            ColumnInfo {
+                data_type: MaybeClickhouseDataType::Invalid("Unknown".to_owned()),
+                ..column("Tags.Count", "UInt32")
+            },
+        ]);
+
+        assert_eq!(
+            columns(&grouped),
+            [
+                (
+                    "Tags.Name",
+                    &MaybeClickhouseDataType::Valid(data_type("Array(String)"))
+                ),
+                (
+                    "Tags.Count",
+                    &MaybeClickhouseDataType::Invalid("Unknown".to_owned())
+                ),
+            ]
+        );
+    }
 }
@@ -7,12 +7,14 @@ SELECT toJSONString(
                 t.table_catalog,
                 t.table_comment,
                 if(empty(st.primary_key), null, st.primary_key),
+                if(empty(st.sampling_key), null, st.sampling_key),
                 toString(t.table_type),
+                st.engine,
                 v.view_definition,
                 c.columns
             )
         ),
-        'Array(Tuple(table_name String, table_schema String, table_catalog String, table_comment Nullable(String), primary_key Nullable(String), table_type String, view_definition String, columns Array(Tuple(column_name String, data_type String, is_nullable Bool, is_in_primary_key Bool))))'
+        'Array(Tuple(table_name String, table_schema String, table_catalog String, table_comment Nullable(String), primary_key Nullable(String), sampling_key Nullable(String), table_type String, engine String, view_definition String, columns Array(Tuple(column_name String, data_type String, is_nullable Bool, is_in_primary_key Bool))))'
     )
 )
 FROM INFORMATION_SCHEMA.TABLES AS t
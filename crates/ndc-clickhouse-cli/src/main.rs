@@ -3,11 +3,16 @@ use common::{
     clickhouse_parser::parameterized_query::{
         Parameter, ParameterizedQuery, ParameterizedQueryElement,
     },
-    config::ConnectionConfig,
+    config::{
+        apply_http_client_config, get_connection_configuration, ConfigurationEnvironment,
+        NAMESPACE_SEPARATOR,
+    },
     config_file::{
         MaybeClickhouseDataType, ParameterizedQueryConfigFile, PrimaryKey, ReturnType,
-        ServerConfigFile, TableConfigFile, CONFIG_FILE_NAME, CONFIG_SCHEMA_FILE_NAME,
+        ScalarTypesConfig, ServerConfigFile, TableConfigFile, CONFIG_FILE_NAME,
+        CONFIG_SCHEMA_FILE_NAME,
     },
+    schema::type_definition::ClickHouseTypeDefinition,
 };
 use database_introspection::{introspect_database, TableInfo};
 use ndc_clickhouse_core::connector::setup::ClickhouseConnectorSetup;
@@ -15,7 +20,7 @@ use ndc_models::{CollectionName, FieldName};
 use ndc_sdk_core::schema::print_schema_and_capabilities;
 use schemars::schema_for;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     env,
     error::Error,
     io,
@@ -74,12 +79,13 @@ enum Command {
         url: String,
         #[arg(long = "clickhouse-username", value_name = "USERNAME", env = "CLICKHOUSE_USERNAME", default_value_t = String::from("default"))]
         username: String,
+        /// Optional when CLICKHOUSE_PASSWORD_FILE or a bearer token is set
         #[arg(
             long = "clickhouse-password",
             value_name = "PASSWORD",
             env = "CLICKHOUSE_PASSWORD"
         )]
-        password: String,
+        password: Option<String>,
     },
     Validate {},
     Watch {},
@@ -138,11 +144,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
             username,
             password,
         } => {
-            let connection = ConnectionConfig {
-                url,
-                username,
-                password,
-            };
+            // read the remaining connection options (tls, proxy, timeouts, credential files...)
+            // from the environment, the same way the connector does
+            let mut env: HashMap<String, String> = env::vars().collect();
+            env.insert("CLICKHOUSE_URL".to_owned(), url);
+            env.insert("CLICKHOUSE_USERNAME".to_owned(), username);
+            if let Some(password) = password {
+                env.insert("CLICKHOUSE_PASSWORD".to_owned(), password);
+            }
+            let mut connection = get_connection_configuration(
+                &ConfigurationEnvironment::from_simulated_environment(env),
+            )?;
+
+            let file_path = context_path.join(CONFIG_FILE_NAME);
+            if let Some(http_client) = read_config_file(&file_path)
+                .await?
+                .and_then(|config| config.http_client)
+            {
+                apply_http_client_config(&mut connection, &http_client, &file_path)?;
+            }
 
             let introspection = introspect_database(&connection).await?;
             let config = update_tables_config(&context_path, &introspection).await?;
@@ -202,11 +222,18 @@ async fn update_tables_config(
 
     let old_config = read_config_file(&file_path).await?;
 
+    let scalar_types = old_config
+        .as_ref()
+        .map(|old_config| old_config.scalar_types.to_owned())
+        .unwrap_or_default();
+    let mut reserved_names = get_reserved_names(introspection, &old_config, &scalar_types);
+
     let tables = introspection
         .iter()
         .map(|table| {
             let old_table_config = get_old_table_config(table, &old_config);
-            let table_alias = get_table_alias(table, &old_table_config);
+            let table_alias =
+                get_table_alias(table, &old_table_config, &scalar_types, &mut reserved_names);
 
             let arguments = ParameterizedQuery::from_str(&table.view_definition)
                 // when unable to parse, default to empty arguments list
@@ -239,7 +266,17 @@ async fn update_tables_config(
                         })
                         .collect(),
                 }),
+                sampling_key: table.sampling_key.to_owned(),
                 arguments,
+                r#final: old_table_config
+                    .map(|(_, old_table)| old_table.r#final)
+                    .unwrap_or_else(|| engine_requires_final(&table.engine)),
+                prewhere: old_table_config
+                    .map(|(_, old_table)| old_table.prewhere.to_owned())
+                    .unwrap_or_default(),
+                settings: old_table_config
+                    .map(|(_, old_table)| old_table.settings.to_owned())
+                    .unwrap_or_default(),
                 return_type: get_table_return_type(
                     table,
                     &old_table_config,
@@ -259,6 +296,32 @@ async fn update_tables_config(
             .as_ref()
             .map(|old_config| old_config.queries.to_owned())
             .unwrap_or_default(),
+        timezone: old_config
+            .as_ref()
+            .and_then(|old_config| old_config.timezone.to_owned()),
+        scalar_types: old_config
+            .as_ref()
+            .map(|old_config| old_config.scalar_types.to_owned())
+            .unwrap_or_default(),
+        settings: old_config
+            .as_ref()
+            .map(|old_config| old_config.settings.to_owned())
+            .unwrap_or_default(),
+        max_response_bytes: old_config
+            .as_ref()
+            .and_then(|old_config| old_config.max_response_bytes),
+        role_forwarding: old_config
+            .as_ref()
+            .and_then(|old_config| old_config.role_forwarding.to_owned()),
+        tenancy: old_config
+            .as_ref()
+            .and_then(|old_config| old_config.tenancy.to_owned()),
+        database_routing: old_config
+            .as_ref()
+            .and_then(|old_config| old_config.database_routing.to_owned()),
+        http_client: old_config
+            .as_ref()
+            .and_then(|old_config| old_config.http_client.to_owned()),
     };
     let config_schema = schema_for!(ServerConfigFile);
 
@@ -458,22 +521,120 @@ fn get_old_table_config<'a>(
     })
 }
 
+/// Names that new table aliases must not collide with:
+/// aliases preserved from the old configuration, query aliases, and the type names of their columns
+fn get_reserved_names(
+    introspection: &[TableInfo],
+    old_config: &Option<ServerConfigFile>,
+    scalar_types: &ScalarTypesConfig,
+) -> BTreeSet<String> {
+    let mut reserved_names = BTreeSet::new();
+
+    if let Some(old_config) = old_config {
+        reserved_names.extend(old_config.queries.keys().map(ToString::to_string));
+    }
+
+    for table in introspection {
+        match get_old_table_config(table, old_config) {
+            Some((old_table_alias, _)) => {
+                let (scalar_names, object_names) =
+                    get_type_names(table, old_table_alias.inner(), scalar_types);
+                reserved_names.insert(old_table_alias.to_string());
+                reserved_names.extend(scalar_names);
+                reserved_names.extend(object_names);
+            }
+            None => {
+                // object type names depend on the alias, so are reserved once the alias is chosen
+                let (scalar_names, _) = get_type_names(table, "", scalar_types);
+                reserved_names.extend(scalar_names);
+            }
+        }
+    }
+
+    reserved_names
+}
+
+/// The scalar and object type names generated for the columns of a table.
+/// Scalar type names do not depend on the table alias, object type names are namespaced by it
+fn get_type_names(
+    table: &TableInfo,
+    table_alias: &str,
+    scalar_types: &ScalarTypesConfig,
+) -> (Vec<String>, Vec<String>) {
+    let mut scalar_names = vec![];
+    let mut object_names = vec![];
+
+    for column in &table.columns {
+        if let MaybeClickhouseDataType::Valid(data_type) = &column.data_type {
+            let type_definitions = ClickHouseTypeDefinition::from_table_column(
+                data_type,
+                &column.column_name.to_owned().into(),
+                &table_alias.to_owned().into(),
+                NAMESPACE_SEPARATOR,
+                scalar_types,
+            )
+            .type_definitions();
+
+            scalar_names.extend(
+                type_definitions
+                    .scalars
+                    .into_iter()
+                    .map(|(name, _)| name.to_string()),
+            );
+            object_names.extend(
+                type_definitions
+                    .objects
+                    .into_iter()
+                    .map(|(name, _)| name.to_string()),
+            );
+        }
+    }
+
+    (scalar_names, object_names)
+}
+
 /// Table aliases default to <schema_name>_<table_name>,
 /// except for tables in the default schema where the table name is used.
-/// Prefer existing, old aliases over creating a new one
+/// Prefer existing, old aliases over creating a new one.
+/// New aliases that collide with a reserved name, or whose object type names would, get a numeric suffix
 fn get_table_alias(
     table: &TableInfo,
     old_table: &Option<(&CollectionName, &TableConfigFile)>,
+    scalar_types: &ScalarTypesConfig,
+    reserved_names: &mut BTreeSet<String>,
 ) -> CollectionName {
     // to preserve any customization, aliases are kept throught updates
     if let Some((old_table_alias, _)) = old_table {
-        old_table_alias.to_string()
-    } else if table.table_schema == "default" {
+        return old_table_alias.to_string().into();
+    }
+
+    let alias = if table.table_schema == "default" {
         table.table_name.to_owned()
     } else {
         format!("{}_{}", table.table_schema, table.table_name)
-    }
-    .into()
+    };
+
+    let (alias, object_names) = (1..)
+        .map(|index| match index {
+            1 => alias.to_owned(),
+            _ => format!("{alias}_{index}"),
+        })
+        .map(|alias| {
+            let (_, object_names) = get_type_names(table, &alias, scalar_types);
+            (alias, object_names)
+        })
+        .find(|(alias, object_names)| {
+            !reserved_names.contains(alias)
+                && !object_names
+                    .iter()
+                    .any(|object_name| reserved_names.contains(object_name))
+        })
+        .expect("an unreserved alias should eventually be found");
+
+    reserved_names.insert(alias.to_owned());
+    reserved_names.extend(object_names);
+
+    alias.into()
 }
 
 /// Given table info, and optionally old table info, get the return type for this table
@@ -561,6 +722,12 @@ fn get_table_return_type(
     })
 }
 
+/// Tables using these engines merge rows in the background, so reads need the FINAL modifier to see the merged result.
+/// Replicated and Shared variants share the same suffix
+fn engine_requires_final(engine: &str) -> bool {
+    engine.ends_with("ReplacingMergeTree") || engine.ends_with("CollapsingMergeTree")
+}
+
 /// Convert an introspected table info into return type columns
 /// Note we intentionally do _not_ validate the column data type
 /// New data types not yet supported in the connector could be considered invalid
@@ -579,3 +746,151 @@ fn get_return_type_columns(table: &TableInfo) -> BTreeMap<FieldName, MaybeClickh
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::clickhouse_parser::datatype::ClickHouseDataType;
+    use database_introspection::{ColumnInfo, TableType};
+
+    fn table(table_schema: &str, table_name: &str, columns: &[(&str, &str)]) -> TableInfo {
+        TableInfo {
+            table_name: table_name.to_owned(),
+            table_schema: table_schema.to_owned(),
+            table_catalog: table_schema.to_owned(),
+            table_comment: None,
+            table_type: TableType::Table,
+            engine: "MergeTree".to_owned(),
+            primary_key: None,
+            sampling_key: None,
+            view_definition: String::new(),
+            columns: columns
+                .iter()
+                .map(|(column_name, data_type)| ColumnInfo {
+                    column_name: (*column_name).to_owned(),
+                    data_type: MaybeClickhouseDataType::Valid(
+                        ClickHouseDataType::from_str(data_type).expect("Should parse data type"),
+                    ),
+                    is_nullable: false,
+                    is_in_primary_key: false,
+                })
+                .collect(),
+        }
+    }
+
+    fn old_config(config: serde_json::Value) -> Option<ServerConfigFile> {
+        Some(serde_json::from_value(config).expect("Should deserialize configuration"))
+    }
+
+    fn new_alias(table: &TableInfo, reserved_names: &mut BTreeSet<String>) -> String {
+        get_table_alias(table, &None, &ScalarTypesConfig::default(), reserved_names).to_string()
+    }
+
+    #[test]
+    fn reserve_query_aliases_old_table_aliases_and_type_names() {
+        let introspection = [
+            table(
+                "Chinook",
+                "Shapes",
+                &[("Point", "Tuple(x Float64, y Float64)")],
+            ),
+            table("Chinook", "Album", &[("Tags", "Tuple(name String)")]),
+        ];
+        let old_config = old_config(serde_json::json!({
+            "$schema": "",
+            "tables": {
+                "Shapes": {
+                    "name": "Shapes",
+                    "schema": "Chinook",
+                    "return_type": { "kind": "definition", "columns": {} }
+                }
+            },
+            "queries": {
+                "TopAlbums": {
+                    "exposed_as": "collection",
+                    "file": "top_albums.sql",
+                    "return_type": { "kind": "definition", "columns": {} }
+                }
+            }
+        }));
+
+        let reserved_names =
+            get_reserved_names(&introspection, &old_config, &ScalarTypesConfig::default());
+
+        assert_eq!(
+            reserved_names.into_iter().collect::<Vec<_>>(),
+            ["Float64", "Shapes", "Shapes.Point", "String", "TopAlbums"]
+        );
+    }
+    #[test]
+    fn derive_aliases_from_schema_and_table_name() {
+        let mut reserved_names = BTreeSet::new();
+
+        assert_eq!(
+            new_alias(&table("default", "Album", &[]), &mut reserved_names),
+            "Album"
+        );
+        assert_eq!(
+            new_alias(&table("Chinook", "Album", &[]), &mut reserved_names),
+            "Chinook_Album"
+        );
+        assert_eq!(
+            reserved_names.into_iter().collect::<Vec<_>>(),
+            ["Album", "Chinook_Album"]
+        );
+    }
+    #[test]
+    fn keep_old_aliases() {
+        let old_table: TableConfigFile = serde_json::from_value(serde_json::json!({
+            "name": "Album",
+            "schema": "Chinook",
+            "return_type": { "kind": "definition", "columns": {} }
+        }))
+        .expect("Should deserialize table configuration");
+        let old_alias: CollectionName = "Albums".to_owned().into();
+
+        let alias = get_table_alias(
+            &table("Chinook", "Album", &[]),
+            &Some((&old_alias, &old_table)),
+            &ScalarTypesConfig::default(),
+            &mut BTreeSet::from(["Albums".to_owned()]),
+        );
+
+        assert_eq!(alias.to_string(), "Albums");
+    }
+    #[test]
+    fn suffix_aliases_colliding_with_reserved_names() {
+        let mut reserved_names = BTreeSet::from(["String".to_owned(), "Shapes.Point".to_owned()]);
+
+        assert_eq!(
+            new_alias(&table("default", "String", &[]), &mut reserved_names),
+            "String_2"
+        );
+        assert_eq!(
+            new_alias(&table("default", "Shapes.Point", &[]), &mut reserved_names),
+            "Shapes.Point_2"
+        );
+    }
+    #[test]
+    fn suffix_aliases_whose_object_type_names_collide() {
+        let mut reserved_names = BTreeSet::new();
+
+        // the first table defines the object type Shapes.Point, which the second table would also define
+        assert_eq!(
+            new_alias(&table("default", "Shapes.Point", &[]), &mut reserved_names),
+            "Shapes.Point"
+        );
+        assert_eq!(
+            new_alias(
+                &table(
+                    "default",
+                    "Shapes",
+                    &[("Point", "Tuple(x Float64, y Float64)")]
+                ),
+                &mut reserved_names
+            ),
+            "Shapes_2"
+        );
+        assert!(reserved_names.contains("Shapes_2.Point"));
+    }
+}
@@ -1,4 +1,5 @@
 pub mod handler;
+pub mod metrics;
 pub mod setup;
 pub mod state;
 
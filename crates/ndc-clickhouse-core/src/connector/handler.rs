@@ -1,3 +1,4 @@
+mod error;
 mod explain;
 mod query;
 
@@ -0,0 +1,127 @@
+use common::client::ClickHouseError;
+use http::StatusCode;
+use ndc_sdk_core::connector::ErrorResponse;
+
+/// Map ClickHouse exceptions caused by the request to 4xx responses, so they are reported to the user.
+/// Other exceptions are internal errors, and failure to reach ClickHouse is reported as a bad gateway
+pub fn clickhouse_error_response(error: ClickHouseError) -> ErrorResponse {
+    ErrorResponse::new(
+        error_status(&error),
+        error.to_string(),
+        error_details(&error),
+    )
+}
+
+fn error_status(error: &ClickHouseError) -> StatusCode {
+    match error {
+        ClickHouseError::Exception { code, .. } => match code {
+            // CANNOT_PARSE_TEXT, NO_SUCH_COLUMN_IN_TABLE, CANNOT_PARSE_INPUT_ASSERTION_FAILED, BAD_ARGUMENTS,
+            // CANNOT_PARSE_DATE, CANNOT_PARSE_DATETIME, ILLEGAL_TYPE_OF_ARGUMENT, UNKNOWN_FUNCTION,
+            // UNKNOWN_IDENTIFIER, TYPE_MISMATCH, UNKNOWN_TABLE, ARGUMENT_OUT_OF_BOUND, CANNOT_CONVERT_TYPE,
+            // CANNOT_PARSE_NUMBER, UNKNOWN_DATABASE, UNKNOWN_QUERY_PARAMETER, BAD_QUERY_PARAMETER
+            6 | 16 | 27 | 36 | 38 | 41 | 43 | 46 | 47 | 53 | 60 | 69 | 70 | 72 | 81 | 456 | 457 => {
+                StatusCode::BAD_REQUEST
+            }
+            // READONLY, ACCESS_DENIED, UNKNOWN_ROLE, SET_NON_GRANTED_ROLE
+            164 | 497 | 511 | 512 => StatusCode::FORBIDDEN,
+            // TOO_MANY_ROWS, TIMEOUT_EXCEEDED, TOO_SLOW, MEMORY_LIMIT_EXCEEDED, TOO_MANY_BYTES, TOO_MANY_ROWS_OR_BYTES
+            158 | 159 | 160 | 241 | 307 | 396 => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+        ClickHouseError::ResponseTooLarge { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        ClickHouseError::Compression(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        ClickHouseError::Request(_) => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Exceptions include the ClickHouse error code and query id, so the failed query can be found in the query log
+fn error_details(error: &ClickHouseError) -> serde_json::Value {
+    match error {
+        ClickHouseError::Exception {
+            code,
+            message,
+            query_id,
+        } => serde_json::json!({
+            "code": code,
+            "message": message,
+            "query_id": query_id,
+        }),
+        ClickHouseError::ResponseTooLarge { .. }
+        | ClickHouseError::Compression(_)
+        | ClickHouseError::Request(_) => serde_json::Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn exception(code: u32) -> ClickHouseError {
+        ClickHouseError::Exception {
+            code,
+            message: format!("Code: {code}. DB::Exception"),
+            query_id: Some("query-id".to_owned()),
+        }
+    }
+
+    #[test]
+    fn map_errors_to_status_codes() {
+        let request_error = reqwest::Client::new()
+            .get("not a url")
+            .build()
+            .expect_err("Should fail to build request");
+
+        let cases = [
+            6, 16, 27, 36, 38, 41, 43, 46, 47, 53, 60, 69, 70, 72, 81, 456, 457,
+        ]
+        .map(|code| (exception(code), StatusCode::BAD_REQUEST))
+        .into_iter()
+        .chain([164, 497, 511, 512].map(|code| (exception(code), StatusCode::FORBIDDEN)))
+        .chain(
+            [158, 159, 160, 241, 307, 396]
+                .map(|code| (exception(code), StatusCode::UNPROCESSABLE_ENTITY)),
+        )
+        .chain([
+            // unknown codes, and codes not caused by the request
+            (exception(1), StatusCode::INTERNAL_SERVER_ERROR),
+            (exception(62), StatusCode::INTERNAL_SERVER_ERROR),
+            (exception(99999), StatusCode::INTERNAL_SERVER_ERROR),
+            (
+                ClickHouseError::ResponseTooLarge {
+                    max_response_bytes: 1024,
+                },
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                ClickHouseError::Compression(io::Error::other("failed")),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                ClickHouseError::Request(request_error),
+                StatusCode::BAD_GATEWAY,
+            ),
+        ]);
+
+        for (error, status) in cases {
+            assert_eq!(error_status(&error), status, "{error}");
+        }
+    }
+    #[test]
+    fn include_exception_details() {
+        assert_eq!(
+            error_details(&exception(60)),
+            serde_json::json!({
+                "code": 60,
+                "message": "Code: 60. DB::Exception",
+                "query_id": "query-id",
+            })
+        );
+        assert_eq!(
+            error_details(&ClickHouseError::ResponseTooLarge {
+                max_response_bytes: 1024
+            }),
+            serde_json::Value::Null
+        );
+    }
+}
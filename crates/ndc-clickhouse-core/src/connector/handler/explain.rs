@@ -29,11 +29,18 @@ pub async fn explain(
         .await
         .map_err(ErrorResponse::from_error)?;
 
+    let query_parameters: Vec<_> = parameters
+        .iter()
+        .cloned()
+        .chain(configuration.query_settings(&request))
+        .collect();
+
     let explain = execute_text_query::<ExplainRow>(
         &client,
         &configuration.connection,
+        state.endpoints(),
         &parameterized_statement,
-        &parameters,
+        &query_parameters,
     )
     .await
     .unwrap_or_else(|err| err.to_string());
@@ -1,8 +1,18 @@
-use common::{client::execute_bytes_query, config::ServerConfig};
+use bytes::Bytes;
+use common::{
+    client::{execute_bytes_query, generate_query_id, kill_query, ClickHouseError, QuerySummary},
+    config::{ConnectionConfig, ServerConfig},
+    endpoints::Endpoints,
+};
 use ndc_models as models;
 use ndc_sdk_core::{connector::ErrorResponse, json_response::JsonResponse};
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+};
 use tracing::Instrument;
 
+use super::error::clickhouse_error_response;
 use crate::{connector::state::ServerState, sql::QueryBuilder};
 
 pub async fn query(
@@ -10,6 +20,23 @@ pub async fn query(
     state: &ServerState,
     request: models::QueryRequest,
 ) -> Result<JsonResponse<models::QueryResponse>, ErrorResponse> {
+    let query_guard = state.metrics().start_query(request.collection.inner());
+
+    let result = execute_query_request(configuration, state, request).await;
+
+    query_guard.finish(result.is_ok());
+
+    result
+}
+
+async fn execute_query_request(
+    configuration: &ServerConfig,
+    state: &ServerState,
+    request: models::QueryRequest,
+) -> Result<JsonResponse<models::QueryResponse>, ErrorResponse> {
+    let metrics = state.metrics();
+    let collection = request.collection.inner();
+
     #[cfg(debug_assertions)]
     {
         use tracing::Level;
@@ -19,6 +46,8 @@ pub async fn query(
         tracing::event!(Level::DEBUG, "Incoming IR" = request_string);
     }
 
+    let build_timer = metrics.start_query_build();
+
     let (statement_string, parameters) =
         tracing::info_span!("Build SQL Query", internal.visibility = "user").in_scope(
             || -> Result<_, ErrorResponse> {
@@ -42,28 +71,82 @@ pub async fn query(
             },
         )?;
 
+    build_timer.observe_duration();
+
     let client = state
         .client(configuration)
         .await
         .map_err(ErrorResponse::from_error)?;
+    let endpoints = state.endpoints();
 
     let execution_span = tracing::info_span!(
         "Execute SQL query",
         db.system = "clickhouse",
-        db.user = configuration.connection.username,
+        db.user = configuration.connection.credentials.username(),
         db.statement = statement_string,
+        db.clickhouse.query_id = tracing::field::Empty,
+        db.clickhouse.read_rows = tracing::field::Empty,
+        db.clickhouse.read_bytes = tracing::field::Empty,
+        db.clickhouse.result_rows = tracing::field::Empty,
+        db.clickhouse.result_bytes = tracing::field::Empty,
+        db.clickhouse.elapsed_ns = tracing::field::Empty,
         internal.visibility = "user",
     );
 
-    let rowsets = execute_bytes_query(
+    // generate the id within the execution span, so each query gets a distinct span id
+    let query_id = execution_span.in_scope(generate_query_id);
+    execution_span.record("db.clickhouse.query_id", &query_id);
+
+    let parameters: Vec<_> = parameters
+        .into_iter()
+        .chain(configuration.query_settings(&request))
+        .chain([
+            ("query_id".to_owned(), query_id.to_owned()),
+            ("log_comment".to_owned(), log_comment(&request)?),
+        ])
+        .collect();
+
+    let execution_timer = metrics.start_query_execution(collection);
+
+    let rowsets = execute_killable_query(
         &client,
         &configuration.connection,
+        endpoints,
         &statement_string,
         &parameters,
+        configuration.max_response_bytes,
+        query_id,
     )
-    .instrument(execution_span)
-    .await
-    .map_err(ErrorResponse::from_error)?;
+    .instrument(execution_span.clone())
+    .await;
+
+    execution_timer.observe_duration();
+
+    let (rowsets, summary) = rowsets.map_err(|err| {
+        let code = match &err {
+            ClickHouseError::Exception { code, .. } => code.to_string(),
+            ClickHouseError::ResponseTooLarge { .. } => "response_too_large".to_owned(),
+            ClickHouseError::Compression(_) => "compression".to_owned(),
+            ClickHouseError::Request(_) => "request".to_owned(),
+        };
+        metrics.record_error(&code);
+
+        clickhouse_error_response(err)
+    })?;
+
+    metrics.record_response(
+        collection,
+        rowsets.len(),
+        summary.as_ref().map_or(0, |summary| summary.read_rows),
+    );
+
+    if let Some(summary) = summary {
+        execution_span.record("db.clickhouse.read_rows", summary.read_rows);
+        execution_span.record("db.clickhouse.read_bytes", summary.read_bytes);
+        execution_span.record("db.clickhouse.result_rows", summary.result_rows);
+        execution_span.record("db.clickhouse.result_bytes", summary.result_bytes);
+        execution_span.record("db.clickhouse.elapsed_ns", summary.elapsed_ns);
+    }
 
     #[cfg(debug_assertions)]
     {
@@ -77,3 +160,388 @@ pub async fn query(
     // we assume the response is a valid JSON string, and send those bytes back without parsing
     Ok(JsonResponse::Serialized(rowsets))
 }
+
+/// Execute a query, killing it unless we know it has finished.
+/// The query has finished if we got a full response or an exception.
+/// Otherwise, we stopped reading the response early, the request timed out, or this future was dropped,
+/// and the query may still be running
+async fn execute_killable_query(
+    client: &reqwest::Client,
+    connection: &ConnectionConfig,
+    endpoints: &Arc<Endpoints>,
+    statement: &str,
+    parameters: &Vec<(String, String)>,
+    max_response_bytes: Option<u64>,
+    query_id: String,
+) -> Result<(Bytes, Option<QuerySummary>), ClickHouseError> {
+    let kill_query_guard = KillQueryOnDrop::new(client, connection, endpoints, query_id);
+
+    let result = execute_bytes_query(
+        client,
+        connection,
+        endpoints,
+        statement,
+        parameters,
+        max_response_bytes,
+    )
+    .await;
+
+    if matches!(result, Ok(_) | Err(ClickHouseError::Exception { .. })) {
+        kill_query_guard.disarm();
+    }
+
+    result
+}
+
+/// A comment recorded in system.query_log, identifying the collection and request a query was generated for.
+/// Identical requests have the same hash, which helps find repeated queries
+fn log_comment(request: &models::QueryRequest) -> Result<String, ErrorResponse> {
+    let request_string = serde_json::to_string(request).map_err(ErrorResponse::from_error)?;
+
+    let mut hasher = DefaultHasher::new();
+    request_string.hash(&mut hasher);
+    let request_hash = format!("{:016x}", hasher.finish());
+
+    let comment = serde_json::json!({
+        "collection": request.collection,
+        "request_hash": request_hash,
+    });
+
+    Ok(comment.to_string())
+}
+
+/// Kills a running query when dropped, unless disarmed first.
+/// When the engine times out or the client disconnects, the request future is dropped,
+/// but ClickHouse would keep running the query unless we explicitly cancel it
+struct KillQueryOnDrop {
+    client: reqwest::Client,
+    connection: ConnectionConfig,
+    endpoints: Arc<Endpoints>,
+    query_id: Option<String>,
+}
+
+impl KillQueryOnDrop {
+    fn new(
+        client: &reqwest::Client,
+        connection: &ConnectionConfig,
+        endpoints: &Arc<Endpoints>,
+        query_id: String,
+    ) -> Self {
+        Self {
+            client: client.clone(),
+            connection: connection.clone(),
+            endpoints: endpoints.clone(),
+            query_id: Some(query_id),
+        }
+    }
+    fn disarm(mut self) {
+        self.query_id = None;
+    }
+}
+
+impl Drop for KillQueryOnDrop {
+    fn drop(&mut self) {
+        let query_id = match self.query_id.take() {
+            Some(query_id) => query_id,
+            None => return,
+        };
+
+        // we cannot await in drop, so kill the query in a separate task
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        let client = self.client.clone();
+        let connection = self.connection.clone();
+        let endpoints = self.endpoints.clone();
+
+        handle.spawn(async move {
+            tracing::info!(query_id, "Query did not complete, killing query");
+
+            if let Err(err) = kill_query(&client, &connection, &endpoints, &query_id).await {
+                tracing::warn!(query_id, "Failed to kill query: {err}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::config::{get_connection_configuration, ConfigurationEnvironment};
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Mutex,
+        thread,
+        time::Duration,
+    };
+
+    /// A minimal HTTP server, answering each request with the response built by `respond`.
+    /// Request bodies are recorded, so tests can check which statements were sent
+    struct MockServer {
+        url: String,
+        statements: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockServer {
+        fn start(respond: impl Fn(&str) -> Vec<u8> + Send + Sync + 'static) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").expect("Should bind mock server");
+            let url = format!(
+                "http://{}/",
+                listener.local_addr().expect("Should have local address")
+            );
+            let statements = Arc::new(Mutex::new(vec![]));
+            let recorded_statements = statements.clone();
+            let respond = Arc::new(respond);
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(mut stream) = stream else { continue };
+                    let recorded_statements = recorded_statements.clone();
+                    let respond = respond.clone();
+
+                    // queries may still be running when the kill is sent, so connections are handled concurrently
+                    thread::spawn(move || {
+                        let Some(statement) = read_statement(&mut stream) else {
+                            return;
+                        };
+
+                        recorded_statements
+                            .lock()
+                            .expect("Should lock statements")
+                            .push(statement.clone());
+
+                        // the client may have stopped reading, in which case writing fails
+                        let _ = stream.write_all(&respond(&statement));
+                    });
+                }
+            });
+
+            Self { url, statements }
+        }
+        fn statements(&self) -> Vec<String> {
+            self.statements
+                .lock()
+                .expect("Should lock statements")
+                .clone()
+        }
+        fn kill_statements(&self) -> Vec<String> {
+            self.statements()
+                .into_iter()
+                .filter(|statement| statement.starts_with("KILL QUERY"))
+                .collect()
+        }
+        /// Wait for the connector to kill queries, which it does in a background task
+        async fn wait_for_kill_statements(&self) -> Vec<String> {
+            for _ in 0..100 {
+                let kill_statements = self.kill_statements();
+                if !kill_statements.is_empty() {
+                    return kill_statements;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+
+            vec![]
+        }
+    }
+
+    fn read_statement(stream: &mut TcpStream) -> Option<String> {
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            if line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    content_length = value.trim().parse().ok()?;
+                }
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).ok()?;
+
+        String::from_utf8(body).ok()
+    }
+
+    fn response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            body.len()
+        );
+        for (name, value) in headers {
+            response.push_str(&format!("{name}: {value}\r\n"));
+        }
+        response.push_str("\r\n");
+
+        let mut response = response.into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    fn connection(url: &str) -> (reqwest::Client, ConnectionConfig, Arc<Endpoints>) {
+        let env = ConfigurationEnvironment::from_simulated_environment(HashMap::from([
+            ("CLICKHOUSE_URL".to_owned(), url.to_owned()),
+            ("CLICKHOUSE_USERNAME".to_owned(), "default".to_owned()),
+            ("CLICKHOUSE_PASSWORD".to_owned(), "".to_owned()),
+            ("CLICKHOUSE_MAX_ATTEMPTS".to_owned(), "1".to_owned()),
+        ]));
+        let connection =
+            get_connection_configuration(&env).expect("Should read connection configuration");
+        let endpoints = Arc::new(Endpoints::new(&connection));
+
+        (reqwest::Client::new(), connection, endpoints)
+    }
+
+    async fn execute(
+        server: &MockServer,
+        max_response_bytes: Option<u64>,
+    ) -> Result<(Bytes, Option<QuerySummary>), ClickHouseError> {
+        let (client, connection, endpoints) = connection(&server.url);
+
+        execute_killable_query(
+            &client,
+            &connection,
+            &endpoints,
+            "SELECT 1",
+            &vec![],
+            max_response_bytes,
+            "query-id".to_owned(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn kill_query_when_dropped() {
+        let server = MockServer::start(|statement| {
+            if statement.starts_with("SELECT") {
+                // a long running query
+                thread::sleep(Duration::from_secs(5));
+            }
+            response("200 OK", &[], b"")
+        });
+
+        // the query future is dropped when the engine times out or the client disconnects
+        let result = tokio::time::timeout(Duration::from_millis(100), execute(&server, None)).await;
+        assert!(result.is_err(), "query should not have completed");
+
+        assert_eq!(
+            server.wait_for_kill_statements().await,
+            ["KILL QUERY WHERE query_id = 'query-id' ASYNC"]
+        );
+    }
+    #[tokio::test]
+    async fn kill_query_when_response_too_large() {
+        let server = MockServer::start(|statement| {
+            if statement.starts_with("SELECT") {
+                response("200 OK", &[], &[b'1'; 1000])
+            } else {
+                response("200 OK", &[], b"")
+            }
+        });
+
+        let error = execute(&server, Some(100))
+            .await
+            .expect_err("Query should fail");
+
+        assert!(matches!(
+            error,
+            ClickHouseError::ResponseTooLarge {
+                max_response_bytes: 100
+            }
+        ));
+        assert_eq!(
+            server.wait_for_kill_statements().await,
+            ["KILL QUERY WHERE query_id = 'query-id' ASYNC"]
+        );
+    }
+    #[tokio::test]
+    async fn read_response_up_to_maximum_size() {
+        let server = MockServer::start(|_| response("200 OK", &[], &[b'1'; 100]));
+
+        let (rowsets, _) = execute(&server, Some(100))
+            .await
+            .expect("Query should succeed");
+
+        assert_eq!(rowsets.len(), 100);
+    }
+    #[tokio::test]
+    async fn do_not_kill_completed_query() {
+        let server = MockServer::start(|_| response("200 OK", &[], b"[]"));
+
+        let (rowsets, _) = execute(&server, None).await.expect("Query should succeed");
+        assert_eq!(rowsets, "[]");
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(server.statements(), ["SELECT 1"]);
+    }
+    #[tokio::test]
+    async fn do_not_kill_query_that_raised_exception() {
+        let server = MockServer::start(|_| {
+            response(
+                "404 Not Found",
+                &[("X-ClickHouse-Exception-Code", "60")],
+                b"Code: 60. DB::Exception: Unknown table",
+            )
+        });
+
+        let error = execute(&server, None).await.expect_err("Query should fail");
+        assert!(matches!(error, ClickHouseError::Exception { code: 60, .. }));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(server.statements(), ["SELECT 1"]);
+    }
+
+    fn request(collection: &str, limit: u32) -> models::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "collection": collection,
+            "query": {
+                "fields": {
+                    "Name": { "type": "column", "column": "Name" }
+                },
+                "limit": limit
+            },
+            "arguments": {},
+            "collection_relationships": {}
+        }))
+        .expect("Should parse request")
+    }
+
+    #[test]
+    fn identify_collection_and_request_in_log_comment() {
+        let comment = log_comment(&request("Artist", 10)).expect("Should build log comment");
+        let comment: serde_json::Value =
+            serde_json::from_str(&comment).expect("Log comment should be json");
+
+        assert_eq!(comment["collection"], "Artist");
+
+        let request_hash = comment["request_hash"]
+            .as_str()
+            .expect("Request hash should be a string");
+        assert_eq!(request_hash.len(), 16);
+        assert!(request_hash.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+    #[test]
+    fn hash_identical_requests_identically() {
+        let comment = |request: &models::QueryRequest| {
+            log_comment(request).expect("Should build log comment")
+        };
+
+        assert_eq!(
+            comment(&request("Artist", 10)),
+            comment(&request("Artist", 10))
+        );
+        assert_ne!(
+            comment(&request("Artist", 10)),
+            comment(&request("Artist", 20))
+        );
+    }
+}
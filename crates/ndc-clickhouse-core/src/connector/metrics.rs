@@ -0,0 +1,181 @@
+use prometheus::{
+    exponential_buckets, Histogram, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec,
+    IntGauge, Opts, Registry,
+};
+
+/// Metrics for query execution, registered with the connector's prometheus registry
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    queries_total: IntCounterVec,
+    queries_in_flight: IntGauge,
+    query_build_duration: Histogram,
+    query_execution_duration: HistogramVec,
+    response_bytes: Histogram,
+    rows_read_total: IntCounterVec,
+    errors_total: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new(registry: &Registry) -> Result<Self, prometheus::Error> {
+        let queries_total = IntCounterVec::new(
+            Opts::new(
+                "ndc_clickhouse_queries_total",
+                "Number of queries by collection and outcome",
+            ),
+            &["collection", "outcome"],
+        )?;
+        let queries_in_flight = IntGauge::new(
+            "ndc_clickhouse_queries_in_flight",
+            "Number of queries currently being executed",
+        )?;
+        let query_build_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "ndc_clickhouse_query_build_duration_seconds",
+                "Time taken to generate SQL for a query request",
+            )
+            .buckets(exponential_buckets(0.0001, 4.0, 8)?),
+        )?;
+        let query_execution_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "ndc_clickhouse_query_execution_duration_seconds",
+                "Time taken for ClickHouse to execute a query and return the response",
+            )
+            .buckets(exponential_buckets(0.005, 2.0, 14)?),
+            &["collection"],
+        )?;
+        let response_bytes = Histogram::with_opts(
+            HistogramOpts::new(
+                "ndc_clickhouse_response_bytes",
+                "Size of query responses returned by ClickHouse",
+            )
+            .buckets(exponential_buckets(256.0, 4.0, 10)?),
+        )?;
+        let rows_read_total = IntCounterVec::new(
+            Opts::new(
+                "ndc_clickhouse_rows_read_total",
+                "Number of rows read by ClickHouse, as reported in the query summary",
+            ),
+            &["collection"],
+        )?;
+        let errors_total = IntCounterVec::new(
+            Opts::new(
+                "ndc_clickhouse_errors_total",
+                "Number of failed queries by error code",
+            ),
+            &["code"],
+        )?;
+
+        registry.register(Box::new(queries_total.clone()))?;
+        registry.register(Box::new(queries_in_flight.clone()))?;
+        registry.register(Box::new(query_build_duration.clone()))?;
+        registry.register(Box::new(query_execution_duration.clone()))?;
+        registry.register(Box::new(response_bytes.clone()))?;
+        registry.register(Box::new(rows_read_total.clone()))?;
+        registry.register(Box::new(errors_total.clone()))?;
+
+        Ok(Self {
+            queries_total,
+            queries_in_flight,
+            query_build_duration,
+            query_execution_duration,
+            response_bytes,
+            rows_read_total,
+            errors_total,
+        })
+    }
+    /// Track a query request. The query is counted as in flight until the returned guard is dropped
+    pub fn start_query(&self, collection: &str) -> QueryGuard {
+        self.queries_in_flight.inc();
+
+        QueryGuard {
+            metrics: self.clone(),
+            collection: collection.to_owned(),
+            outcome: None,
+        }
+    }
+    pub fn start_query_build(&self) -> HistogramTimer {
+        self.query_build_duration.start_timer()
+    }
+    pub fn start_query_execution(&self, collection: &str) -> HistogramTimer {
+        self.query_execution_duration
+            .with_label_values(&[collection])
+            .start_timer()
+    }
+    pub fn record_response(&self, collection: &str, response_bytes: usize, rows_read: u64) {
+        self.response_bytes.observe(response_bytes as f64);
+        self.rows_read_total
+            .with_label_values(&[collection])
+            .inc_by(rows_read);
+    }
+    pub fn record_error(&self, code: &str) {
+        self.errors_total.with_label_values(&[code]).inc();
+    }
+}
+
+/// Counts a query when dropped. Queries that are dropped before finishing are counted as cancelled
+pub struct QueryGuard {
+    metrics: Metrics,
+    collection: String,
+    outcome: Option<&'static str>,
+}
+
+impl QueryGuard {
+    pub fn finish(mut self, success: bool) {
+        self.outcome = Some(if success { "success" } else { "error" });
+    }
+}
+
+impl Drop for QueryGuard {
+    fn drop(&mut self) {
+        self.metrics.queries_in_flight.dec();
+        self.metrics
+            .queries_total
+            .with_label_values(&[
+                self.collection.as_str(),
+                self.outcome.unwrap_or("cancelled"),
+            ])
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queries_total(metrics: &Metrics, collection: &str, outcome: &str) -> u64 {
+        metrics
+            .queries_total
+            .with_label_values(&[collection, outcome])
+            .get()
+    }
+
+    #[test]
+    fn count_queries_by_outcome() {
+        let metrics = Metrics::new(&Registry::new()).expect("Should register metrics");
+
+        metrics.start_query("Artist").finish(true);
+        metrics.start_query("Artist").finish(true);
+        metrics.start_query("Artist").finish(false);
+        drop(metrics.start_query("Album"));
+
+        assert_eq!(queries_total(&metrics, "Artist", "success"), 2);
+        assert_eq!(queries_total(&metrics, "Artist", "error"), 1);
+        assert_eq!(queries_total(&metrics, "Artist", "cancelled"), 0);
+        assert_eq!(queries_total(&metrics, "Album", "cancelled"), 1);
+        assert_eq!(queries_total(&metrics, "Album", "success"), 0);
+    }
+    #[test]
+    fn count_queries_in_flight() {
+        let metrics = Metrics::new(&Registry::new()).expect("Should register metrics");
+
+        let first = metrics.start_query("Artist");
+        let second = metrics.start_query("Artist");
+        assert_eq!(metrics.queries_in_flight.get(), 2);
+
+        first.finish(true);
+        assert_eq!(metrics.queries_in_flight.get(), 1);
+
+        drop(second);
+        assert_eq!(metrics.queries_in_flight.get(), 0);
+    }
+}
@@ -1,4 +1,4 @@
-use super::{state::ServerState, ClickhouseConnector};
+use super::{metrics::Metrics, state::ServerState, ClickhouseConnector};
 use async_trait::async_trait;
 use common::config::{read_server_config, ConfigurationEnvironment};
 use ndc_sdk_core::connector::{self, Connector, ConnectorSetup, ErrorResponse};
@@ -23,9 +23,11 @@ impl ConnectorSetup for ClickhouseConnectorSetup {
     async fn try_init_state(
         &self,
         configuration: &<Self::Connector as Connector>::Configuration,
-        _metrics: &mut prometheus::Registry,
+        metrics: &mut prometheus::Registry,
     ) -> connector::Result<<Self::Connector as Connector>::State> {
-        Ok(ServerState::new(configuration))
+        let metrics = Metrics::new(metrics).map_err(ErrorResponse::from_error)?;
+
+        Ok(ServerState::new(configuration, metrics))
     }
 }
 
@@ -1,21 +1,42 @@
-use common::{client::get_http_client, config::ServerConfig};
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use common::{
+    client::get_http_client, config::ServerConfig, credentials::Credentials, endpoints::Endpoints,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::RwLock, task::JoinHandle};
+
+use super::metrics::Metrics;
 
 #[derive(Debug, Clone)]
 pub struct ServerState {
     client: Arc<RwLock<Option<reqwest::Client>>>,
+    /// endpoint health and in flight requests are tracked across requests, so they are shared by all clones
+    endpoints: Arc<Endpoints>,
+    metrics: Metrics,
+    /// periodically reloads credentials read from files, until the last clone of the state is dropped
+    _credentials_reload: Option<Arc<AbortOnDrop>>,
 }
 
 impl ServerState {
-    pub fn new(config: &ServerConfig) -> ServerState {
+    pub fn new(config: &ServerConfig, metrics: Metrics) -> ServerState {
         // if client creation fails for whatever reason, client should be none.
         let client = get_http_client(&config.connection).ok();
 
         ServerState {
             client: Arc::new(RwLock::new(client)),
+            endpoints: Arc::new(Endpoints::new(&config.connection)),
+            metrics,
+            _credentials_reload: spawn_credentials_reload(
+                &config.connection.credentials,
+                config.connection.credentials_reload_interval,
+            ),
         }
     }
+    pub fn endpoints(&self) -> &Arc<Endpoints> {
+        &self.endpoints
+    }
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
     pub async fn client(&self, config: &ServerConfig) -> Result<reqwest::Client, reqwest::Error> {
         if let Some(client) = &*self.client.read().await {
             Ok(client.clone())
@@ -36,3 +57,108 @@ impl ServerState {
         }
     }
 }
+
+/// Re-read credential files at an interval, so rotated credentials are used without restarting the connector.
+/// Credentials are shared with the configuration, so new values are picked up by subsequent requests
+fn spawn_credentials_reload(
+    credentials: &Credentials,
+    interval: Duration,
+) -> Option<Arc<AbortOnDrop>> {
+    if !credentials.is_reloadable() || interval.is_zero() {
+        return None;
+    }
+
+    let handle = tokio::runtime::Handle::try_current().ok()?;
+    let credentials = credentials.clone();
+
+    let task = handle.spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // the first tick completes immediately, and credentials were just read
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            match credentials.reload().await {
+                Ok(true) => tracing::info!("Reloaded rotated ClickHouse credentials"),
+                Ok(false) => {}
+                Err(err) => tracing::warn!("Failed to reload ClickHouse credentials: {err}"),
+            }
+        }
+    });
+
+    Some(Arc::new(AbortOnDrop(task)))
+}
+
+#[derive(Debug)]
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::credentials::{Authentication, Secret};
+    use std::{env, fs};
+
+    fn token(credentials: &Credentials) -> String {
+        match credentials.authentication() {
+            Authentication::Bearer { token } => token,
+            Authentication::Basic { .. } => panic!("Expected bearer token"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reload_credentials_until_dropped() {
+        let path = env::temp_dir().join(format!(
+            "ndc-clickhouse-reload-task-{}.txt",
+            std::process::id()
+        ));
+        fs::write(&path, "first").expect("Should write file");
+
+        let credentials =
+            Credentials::bearer(Secret::File(path.to_owned())).expect("Should read credentials");
+        let reload = spawn_credentials_reload(&credentials, Duration::from_millis(10))
+            .expect("Should spawn reload task");
+
+        fs::write(&path, "second").expect("Should write file");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(token(&credentials), "second");
+
+        // a file caught mid-rotation does not replace working credentials
+        fs::write(&path, "").expect("Should write file");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(token(&credentials), "second");
+
+        fs::remove_file(&path).expect("Should remove file");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(token(&credentials), "second");
+
+        drop(reload);
+        fs::write(&path, "third").expect("Should write file");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(token(&credentials), "second");
+
+        fs::remove_file(&path).expect("Should remove file");
+    }
+    #[test]
+    fn do_not_reload_credentials_without_files() {
+        let credentials = Credentials::basic(
+            Secret::Value("default".to_owned()),
+            Secret::Value("password".to_owned()),
+        )
+        .expect("Should read credentials");
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("Should build runtime");
+        let _guard = runtime.enter();
+
+        assert!(spawn_credentials_reload(&credentials, Duration::from_millis(10)).is_none());
+    }
+}
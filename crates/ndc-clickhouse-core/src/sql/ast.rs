@@ -58,6 +58,7 @@ pub struct Query {
     with: Vec<WithItem>,
     select: Vec<SelectItem>,
     from: Vec<TableWithJoins>,
+    prewhere: Option<Expr>,
     predicate: Option<Expr>,
     group_by: Vec<Expr>,
     order_by: Vec<OrderByExpr>,
@@ -79,6 +80,9 @@ impl Query {
     pub fn from(self, from: Vec<TableWithJoins>) -> Self {
         Self { from, ..self }
     }
+    pub fn prewhere(self, prewhere: Option<Expr>) -> Self {
+        Self { prewhere, ..self }
+    }
     pub fn predicate(self, predicate: Option<Expr>) -> Self {
         Self { predicate, ..self }
     }
@@ -127,6 +131,9 @@ impl fmt::Display for Query {
         if !self.from.is_empty() {
             write!(f, " FROM {}", display_comma_separated(&self.from))?;
         }
+        if let Some(prewhere) = &self.prewhere {
+            write!(f, " PREWHERE {}", prewhere)?;
+        }
         if let Some(predicate) = &self.predicate {
             write!(f, " WHERE {}", predicate)?;
         }
@@ -341,6 +348,9 @@ pub enum TableFactor {
     Table {
         name: ObjectName,
         alias: Option<Ident>,
+        /// read the table with the FINAL modifier, merging rows at query time
+        r#final: bool,
+        sample: Option<Sample>,
     },
     Derived {
         subquery: Box<Query>,
@@ -360,7 +370,17 @@ impl TableFactor {
     pub fn alias<S: Into<Ident>>(self, alias: S) -> Self {
         let alias = Some(alias.into());
         match self {
-            TableFactor::Table { name, alias: _ } => TableFactor::Table { name, alias },
+            TableFactor::Table {
+                name,
+                alias: _,
+                r#final,
+                sample,
+            } => TableFactor::Table {
+                name,
+                alias,
+                r#final,
+                sample,
+            },
             TableFactor::Derived { subquery, alias: _ } => TableFactor::Derived { subquery, alias },
             TableFactor::TableFunction { function, alias: _ } => {
                 TableFactor::TableFunction { function, alias }
@@ -374,6 +394,40 @@ impl TableFactor {
             },
         }
     }
+    /// Set the FINAL modifier. Only applies to tables, other table factors are returned unchanged
+    pub fn r#final(self, r#final: bool) -> Self {
+        match self {
+            TableFactor::Table {
+                name,
+                alias,
+                r#final: _,
+                sample,
+            } => TableFactor::Table {
+                name,
+                alias,
+                r#final,
+                sample,
+            },
+            _ => self,
+        }
+    }
+    /// Set the SAMPLE clause. Only applies to tables, other table factors are returned unchanged
+    pub fn sample(self, sample: Option<Sample>) -> Self {
+        match self {
+            TableFactor::Table {
+                name,
+                alias,
+                r#final,
+                sample: _,
+            } => TableFactor::Table {
+                name,
+                alias,
+                r#final,
+                sample,
+            },
+            _ => self,
+        }
+    }
     pub fn into_table_with_joins(self, joins: Vec<Join>) -> TableWithJoins {
         TableWithJoins {
             relation: self,
@@ -385,11 +439,22 @@ impl TableFactor {
 impl fmt::Display for TableFactor {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            TableFactor::Table { name, alias } => {
+            TableFactor::Table {
+                name,
+                alias,
+                r#final,
+                sample,
+            } => {
                 write!(f, "{}", name)?;
                 if let Some(alias) = alias {
                     write!(f, " AS {}", alias)?;
                 }
+                if *r#final {
+                    write!(f, " FINAL")?;
+                }
+                if let Some(sample) = sample {
+                    write!(f, " {sample}")?;
+                }
             }
             TableFactor::Derived { subquery, alias } => {
                 write!(f, "({})", subquery)?;
@@ -443,6 +508,24 @@ impl fmt::Display for NativeQuery {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// a ratio between 0 and 1, or an approximate number of rows
+    pub ratio: Value,
+    /// an offset ratio between 0 and 1
+    pub offset: Option<Value>,
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SAMPLE {}", self.ratio)?;
+        if let Some(offset) = &self.offset {
+            write!(f, " OFFSET {offset}")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum NativeQueryElement {
     String(String),
@@ -466,6 +549,8 @@ impl ObjectName {
         TableFactor::Table {
             name: self,
             alias: None,
+            r#final: false,
+            sample: None,
         }
     }
     pub fn into_table_function(self) -> Function {
@@ -839,6 +924,19 @@ impl Value {
             }
         }
 
+        fn is_valid_date(s: &str) -> bool {
+            chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").is_ok()
+        }
+
+        /// timestamps should be ISO-8601 strings. The offset is optional,
+        /// if missing the value is interpreted in the column or session timezone
+        fn is_valid_timestamp(s: &str) -> bool {
+            chrono::DateTime::parse_from_rfc3339(s).is_ok()
+                || chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f").is_ok()
+                || chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f").is_ok()
+                || is_valid_date(s)
+        }
+
         fn map_json_value(
             value: &serde_json::Value,
             data_type: &ClickHouseDataType,
@@ -847,7 +945,29 @@ impl Value {
                 serde_json::Value::Null => Ok(Value::Null),
                 serde_json::Value::Bool(b) => Ok(Value::Boolean(b.to_owned())),
                 serde_json::Value::Number(n) => Ok(Value::Number(n.to_string())),
-                serde_json::Value::String(s) => Ok(Value::SingleQuotedString(s.to_owned())),
+                serde_json::Value::String(s) => match underlying_type(data_type) {
+                    ClickHouseDataType::Date | ClickHouseDataType::Date32 => {
+                        if is_valid_date(s) {
+                            Ok(Value::SingleQuotedString(s.to_owned()))
+                        } else {
+                            Err(QueryBuilderError::InvalidDateTime {
+                                value: s.to_owned(),
+                                data_type: data_type.to_owned().into(),
+                            })
+                        }
+                    }
+                    ClickHouseDataType::DateTime { .. } | ClickHouseDataType::DateTime64 { .. } => {
+                        if is_valid_timestamp(s) {
+                            Ok(Value::SingleQuotedString(s.to_owned()))
+                        } else {
+                            Err(QueryBuilderError::InvalidDateTime {
+                                value: s.to_owned(),
+                                data_type: data_type.to_owned().into(),
+                            })
+                        }
+                    }
+                    _ => Ok(Value::SingleQuotedString(s.to_owned())),
+                },
                 serde_json::Value::Array(arr) => match underlying_type(data_type) {
                     ClickHouseDataType::Array(element_type) => Ok(Value::Array(
                         arr.iter()
@@ -10,25 +10,26 @@ use super::ast::*;
 use binary_comparison_expression::apply_binary_operator;
 use common::{
     clickhouse_parser::{
-        datatype::ClickHouseDataType, parameterized_query::ParameterizedQueryElement,
+        datatype::{ClickHouseDataType, Identifier},
+        parameterized_query::ParameterizedQueryElement,
     },
-    config::ServerConfig,
+    config::{ServerConfig, SAMPLE_OFFSET_ARGUMENT, SAMPLE_RATIO_ARGUMENT},
     schema::{
         binary_comparison_operator::ClickHouseBinaryComparisonOperator,
         single_column_aggregate_function::ClickHouseSingleColumnAggregateFunction,
-        type_definition::ClickHouseTypeDefinition,
+        type_definition::{finalized_aggregate_type, ClickHouseTypeDefinition},
     },
 };
 use comparison_column::ComparisonColumn;
 pub use error::QueryBuilderError;
-use function_expression::apply_function;
+use function_expression::{apply_function, apply_merge_function, function_name};
 use indexmap::IndexMap;
 use ndc_models::{
     self as models, AggregateFunctionName, ArgumentName, CollectionName, FieldName, ObjectTypeName,
     RelationshipName, VariableName,
 };
 use parameter::ParameterBuilder;
-use std::{collections::BTreeMap, iter, str::FromStr};
+use std::{borrow::Cow, collections::BTreeMap, iter, str::FromStr};
 
 pub struct QueryBuilder<'r, 'c> {
     request: &'r models::QueryRequest,
@@ -44,11 +45,17 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
             configuration,
         }
     }
+    /// Build the statement, and the url parameters to send it with.
+    /// These include the `role` parameter when role forwarding is enabled
     pub fn build_parameterized(&self) -> Result<(Statement, Parameters), QueryBuilderError> {
         let mut parameters = ParameterBuilder::new(false);
         let statement = self.root_query(&mut parameters)?;
 
-        let parameters = parameters.into_parameters();
+        let parameters = parameters
+            .into_parameters()
+            .into_iter()
+            .chain(self.role()?.map(|role| ("role".to_owned(), role)))
+            .collect();
 
         Ok((statement, parameters))
     }
@@ -58,6 +65,45 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
 
         Ok(statement)
     }
+    /// The ClickHouse role to execute the query with, if role forwarding is enabled.
+    /// The role applies to the whole request, so it is read from the root collection arguments
+    fn role(&self) -> Result<Option<String>, QueryBuilderError> {
+        let role_forwarding = match &self.configuration.role_forwarding {
+            Some(role_forwarding) => role_forwarding,
+            None => return Ok(None),
+        };
+
+        let value = self
+            .request_argument(&role_forwarding.argument)?
+            .ok_or_else(|| {
+                QueryBuilderError::MissingRequestArgument(role_forwarding.argument.to_owned())
+            })?;
+
+        match value {
+            serde_json::Value::String(role) if !role.is_empty() => Ok(Some(role.to_owned())),
+            _ => Err(QueryBuilderError::InvalidRequestArgument {
+                argument: role_forwarding.argument.to_owned(),
+                value: value.to_owned(),
+            }),
+        }
+    }
+    /// The value of a request argument, from the root collection arguments.
+    /// Variables are not supported, as the value must be the same for all variable sets. Null values are treated as missing
+    fn request_argument(
+        &self,
+        name: &ArgumentName,
+    ) -> Result<Option<&serde_json::Value>, QueryBuilderError> {
+        match self.request.arguments.get(name) {
+            None
+            | Some(models::Argument::Literal {
+                value: serde_json::Value::Null,
+            }) => Ok(None),
+            Some(models::Argument::Literal { value }) => Ok(Some(value)),
+            Some(models::Argument::Variable { .. }) => Err(QueryBuilderError::NotSupported(
+                format!("request argument {name} from variable"),
+            )),
+        }
+    }
     fn root_query(
         &self,
         parameters: &mut ParameterBuilder,
@@ -216,6 +262,17 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
             None
         };
 
+        // when sampling, counts and sums are scaled to approximate the result over the full table
+        let sample_factor = self.collection_sample(current_collection)?.map(|_| {
+            Function::new_unquoted("any")
+                .args(vec![Expr::CompoundIdentifier(vec![
+                    Ident::new_quoted("_row"),
+                    Ident::new_quoted("_sample_factor"),
+                ])
+                .into_arg()])
+                .into_expr()
+        });
+
         let aggregates = if let Some(aggregates) = &query.aggregates {
             Some(
                 if aggregates.is_empty() {
@@ -224,7 +281,7 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                     let args = aggregates
                         .iter()
                         .map(|(alias, aggregate)| {
-                            Ok(match aggregate {
+                            let expr = match aggregate {
                                 models::Aggregate::StarCount {} => Function::new_unquoted("COUNT")
                                     .args(vec![FunctionArgExpr::Wildcard.into_arg()])
                                     .into_expr(),
@@ -244,18 +301,39 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                                 }
                                 models::Aggregate::SingleColumn {
                                     function,
-                                    column: _,
+                                    column: column_alias,
                                     field_path: _,
                                 } => {
                                     let column = Expr::CompoundIdentifier(vec![
                                         Ident::new_quoted("_row"),
                                         Ident::new_quoted(format!("_agg_{alias}")),
                                     ]);
-                                    apply_function(&aggregate_function(function)?, column)
+                                    let function = aggregate_function(function)?;
+                                    match self.aggregate_state_merge(
+                                        column_alias,
+                                        Some(&function),
+                                        current_collection,
+                                    )? {
+                                        Some(state_function) => {
+                                            apply_merge_function(&state_function, column)
+                                        }
+                                        None => apply_function(&function, column),
+                                    }
                                 }
-                            }
-                            .into_arg()
-                            .with_alias(Ident::new_quoted(alias.as_str())))
+                            };
+
+                            let expr = match &sample_factor {
+                                Some(sample_factor) => {
+                                    let scaling =
+                                        self.sample_scaling(aggregate, current_collection)?;
+                                    apply_sample_scaling(expr, sample_factor, scaling)
+                                }
+                                None => expr,
+                            };
+
+                            Ok(expr
+                                .into_arg()
+                                .with_alias(Ident::new_quoted(alias.as_str())))
                         })
                         .collect::<Result<Vec<_>, QueryBuilderError>>()?;
                     Function::new_unquoted("tuple").args(args).into_expr()
@@ -353,13 +431,23 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                             column,
                             return_type,
                             &self.configuration.namespace_separator,
+                            &self.configuration.scalar_types,
                         );
 
-                        let column_ident =
-                            vec![Ident::new_quoted("_origin"), self.column_ident(column)];
+                        let column_expr = self.column_expr(
+                            &Ident::new_quoted("_origin"),
+                            column,
+                            current_collection,
+                        )?;
 
-                        if let Some((expr, mut joins)) = self.column_accessor(
-                            column_ident,
+                        if self
+                            .column_state_function(column, current_collection)?
+                            .is_some()
+                        {
+                            // aggregate function states are finalized, there are no nested fields to access
+                            select.push(column_expr.into_select(Some(format!("_field_{alias}"))));
+                        } else if let Some((expr, mut joins)) = self.column_accessor(
+                            column_expr.clone(),
                             &column_definition,
                             false,
                             fields.as_ref(),
@@ -369,11 +457,7 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                             select.push(expr.into_select(Some(format!("_field_{alias}"))));
                             base_joins.append(&mut joins);
                         } else {
-                            let expr = Expr::CompoundIdentifier(vec![
-                                Ident::new_quoted("_origin"),
-                                self.column_ident(column),
-                            ]);
-                            select.push(expr.into_select(Some(format!("_field_{alias}"))));
+                            select.push(column_expr.into_select(Some(format!("_field_{alias}"))));
                         }
                     }
                     models::Field::Relationship {
@@ -400,15 +484,44 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
 
         if let Some(aggregates) = &query.aggregates {
             for (alias, aggregate) in aggregates {
-                if let models::Aggregate::ColumnCount { column, .. }
-                | models::Aggregate::SingleColumn { column, .. } = aggregate
-                {
-                    let expr = Expr::CompoundIdentifier(vec![
+                let expr = match aggregate {
+                    models::Aggregate::StarCount {} => continue,
+                    models::Aggregate::SingleColumn {
+                        column, function, ..
+                    } if self
+                        .aggregate_state_merge(
+                            column,
+                            Some(&aggregate_function(function)?),
+                            current_collection,
+                        )?
+                        .is_some() =>
+                    {
+                        // select the raw states, these will be merged by the rowset subquery
+                        Expr::CompoundIdentifier(vec![
+                            Ident::new_quoted("_origin"),
+                            self.column_ident(column),
+                        ])
+                    }
+                    models::Aggregate::ColumnCount { column, .. } => {
+                        self.aggregate_state_merge(column, None, current_collection)?;
+                        self.column_expr(&Ident::new_quoted("_origin"), column, current_collection)?
+                    }
+                    models::Aggregate::SingleColumn { column, .. } => {
+                        self.column_expr(&Ident::new_quoted("_origin"), column, current_collection)?
+                    }
+                };
+                select.push(expr.into_select(Some(format!("_agg_{alias}"))))
+            }
+
+            if self.collection_sample(current_collection)?.is_some() {
+                // the virtual _sample_factor column is used to scale aggregates in the rowset subquery
+                select.push(
+                    Expr::CompoundIdentifier(vec![
                         Ident::new_quoted("_origin"),
-                        self.column_ident(column),
-                    ]);
-                    select.push(expr.into_select(Some(format!("_agg_{alias}"))))
-                }
+                        Ident::new_quoted("_sample_factor"),
+                    ])
+                    .into_select(Some("_sample_factor")),
+                )
             }
         }
 
@@ -436,7 +549,35 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
             select.push(Expr::Value(Value::Null).into_select::<String>(None))
         }
 
-        let (predicate, predicate_joins) = if let Some(predicate) = &query.predicate {
+        let (prewhere_predicates, predicate) =
+            self.split_prewhere(query.predicate.as_ref(), current_collection);
+
+        let prewhere = prewhere_predicates
+            .into_iter()
+            .map(|expression| {
+                let (expr, joins) = self.filter_expression(
+                    expression,
+                    &Ident::new_quoted("_origin"),
+                    current_collection,
+                    true,
+                    &mut 0,
+                    parameters,
+                )?;
+
+                // prewhere candidates only reference the origin table, so never require joins
+                if joins.is_empty() {
+                    Ok(expr)
+                } else {
+                    Err(QueryBuilderError::Unexpected(
+                        "PREWHERE predicate requires a join".to_string(),
+                    ))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .reduce(and_reducer);
+
+        let (predicate, predicate_joins) = if let Some(predicate) = &predicate {
             self.filter_expression(
                 predicate,
                 &Ident::new_quoted("_origin"),
@@ -450,7 +591,8 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
             (None, vec![])
         };
 
-        let (order_by_exprs, order_by_joins) = self.order_by(&query.order_by, parameters)?;
+        let (order_by_exprs, order_by_joins) =
+            self.order_by(&query.order_by, current_collection, parameters)?;
 
         let joins = base_joins
             .into_iter()
@@ -505,15 +647,74 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
         Ok(Query::new()
             .select(select)
             .from(from)
+            .prewhere(prewhere)
             .predicate(predicate)
             .order_by(order_by_exprs)
             .limit_by(limit_by)
             .limit(limit)
             .offset(offset))
     }
+    /// Split the top level conjuncts of a predicate into those that can be moved to PREWHERE, and the remaining predicate.
+    /// Only comparisons of primary key or configured prewhere columns to literal values are moved.
+    /// PREWHERE applies to the first table in the FROM clause, so we don't generate it when variables are joined in first,
+    /// or when the table is wrapped in a subquery restricting it to a tenant.
+    /// With FINAL, PREWHERE is evaluated before rows are merged, which is only safe for sorting key columns.
+    /// The primary key is a prefix of the sorting key, so configured prewhere columns are ignored for those tables
+    fn split_prewhere<'e>(
+        &self,
+        predicate: Option<&'e models::Expression>,
+        collection: &CollectionContext,
+    ) -> (
+        Vec<&'e models::Expression>,
+        Option<Cow<'e, models::Expression>>,
+    ) {
+        let predicate = match predicate {
+            Some(predicate) => predicate,
+            None => return (vec![], None),
+        };
+
+        let table = match self.configuration.tables.get(collection.alias()) {
+            Some(table)
+                if table.prewhere.enabled
+                    && table.arguments.is_empty()
+                    && self.request.variables.is_none()
+                    && self
+                        .configuration
+                        .tenant_column(collection.alias())
+                        .is_none() =>
+            {
+                table
+            }
+            _ => return (vec![], Some(Cow::Borrowed(predicate))),
+        };
+
+        let prewhere_columns: Vec<&FieldName> = table
+            .primary_key
+            .iter()
+            .flat_map(|primary_key| primary_key.columns.iter())
+            .chain(table.prewhere.columns.iter().filter(|_| !table.r#final))
+            .collect();
+
+        let (prewhere, remaining): (Vec<_>, Vec<_>) = conjuncts(predicate)
+            .into_iter()
+            .partition(|expression| is_prewhere_candidate(expression, &prewhere_columns));
+
+        if prewhere.is_empty() {
+            // leave the predicate untouched
+            (prewhere, Some(Cow::Borrowed(predicate)))
+        } else if remaining.is_empty() {
+            (prewhere, None)
+        } else {
+            let remaining = models::Expression::And {
+                expressions: remaining.into_iter().cloned().collect(),
+            };
+            (prewhere, Some(Cow::Owned(remaining)))
+        }
+    }
     fn order_by(
         &self,
         order_by: &Option<models::OrderBy>,
+        current_collection: &CollectionContext,
         parameters: &mut ParameterBuilder,
     ) -> Result<(Vec<OrderByExpr>, Vec<Join>), QueryBuilderError> {
         let mut order_by_exprs = vec![];
@@ -529,10 +730,11 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                         path,
                         field_path: _,
                     } if path.is_empty() => {
-                        let expr = Expr::CompoundIdentifier(vec![
-                            Ident::new_quoted("_origin"),
-                            self.column_ident(name),
-                        ]);
+                        let expr = self.column_expr(
+                            &Ident::new_quoted("_origin"),
+                            name,
+                            current_collection,
+                        )?;
                         let asc = match &element.order_direction {
                             models::OrderDirection::Asc => Some(true),
                             models::OrderDirection::Desc => Some(false),
@@ -653,6 +855,7 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                             }
 
                             let mut last_join_alias = join_alias;
+                            let mut last_collection_context = relationship_collection;
 
                             for path_element in path.iter().skip(1) {
                                 let join_alias =
@@ -733,6 +936,7 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                                 }
 
                                 last_join_alias = join_alias;
+                                last_collection_context = relationship_collection;
                             }
 
                             match &element.target {
@@ -741,10 +945,11 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                                     path: _,
                                     field_path: _,
                                 } => {
-                                    let column = Expr::CompoundIdentifier(vec![
-                                        last_join_alias,
-                                        self.column_ident(name),
-                                    ]);
+                                    let column = self.column_expr(
+                                        &last_join_alias,
+                                        name,
+                                        &last_collection_context,
+                                    )?;
                                     group_by.push(column.clone());
                                     select.push(column.into_select(Some("_order_by_value")));
                                 }
@@ -754,14 +959,31 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                                     path: _,
                                     field_path: _,
                                 } => {
-                                    let column = Expr::CompoundIdentifier(vec![
-                                        last_join_alias,
-                                        self.column_ident(column),
-                                    ]);
-                                    select.push(
-                                        apply_function(&aggregate_function(function)?, column)
-                                            .into_select(Some("_order_by_value")),
-                                    );
+                                    let function = aggregate_function(function)?;
+                                    let expr = if let Some(state_function) = self
+                                        .aggregate_state_merge(
+                                            column,
+                                            Some(&function),
+                                            &last_collection_context,
+                                        )? {
+                                        apply_merge_function(
+                                            &state_function,
+                                            Expr::CompoundIdentifier(vec![
+                                                last_join_alias,
+                                                self.column_ident(column),
+                                            ]),
+                                        )
+                                    } else {
+                                        apply_function(
+                                            &function,
+                                            self.column_expr(
+                                                &last_join_alias,
+                                                column,
+                                                &last_collection_context,
+                                            )?,
+                                        )
+                                    };
+                                    select.push(expr.into_select(Some("_order_by_value")));
                                 }
                                 models::OrderByTarget::StarCountAggregate { path: _ } => {
                                     let count = Function::new_unquoted("COUNT")
@@ -1525,11 +1747,13 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
 
                                 select.push(
                                     Function::new_unquoted("groupArray")
-                                        .args(vec![Expr::CompoundIdentifier(vec![
-                                            last_join_alias,
-                                            self.column_ident(comparison_column_name),
-                                        ])
-                                        .into_arg()])
+                                        .args(vec![self
+                                            .column_expr(
+                                                &last_join_alias,
+                                                comparison_column_name,
+                                                &last_collection_context,
+                                            )?
+                                            .into_arg()])
                                         .into_expr()
                                         .into_select(Some("_values")),
                                 );
@@ -1699,10 +1923,11 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                             last_collection_context = relationship_collection;
                         }
 
-                        let column_ident = Expr::CompoundIdentifier(vec![
-                            last_join_alias,
-                            self.column_ident(comparison_column_name),
-                        ]);
+                        let column_ident = self.column_expr(
+                            &last_join_alias,
+                            comparison_column_name,
+                            &last_collection_context,
+                        )?;
 
                         Ok(ComparisonColumn::new_flat(
                             column_ident,
@@ -1715,10 +1940,11 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                         ))
                     }
                 } else {
-                    let column_ident = Expr::CompoundIdentifier(vec![
-                        current_join_alias.clone(),
-                        self.column_ident(comparison_column_name),
-                    ]);
+                    let column_ident = self.column_expr(
+                        current_join_alias,
+                        comparison_column_name,
+                        current_collection,
+                    )?;
                     Ok(ComparisonColumn::new_simple(
                         column_ident,
                         self.column_data_type(comparison_column_name, current_collection)?,
@@ -1730,10 +1956,8 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                 field_path: _,
             } => {
                 if current_is_origin {
-                    let column_ident = Expr::CompoundIdentifier(vec![
-                        current_join_alias.clone(),
-                        self.column_ident(name),
-                    ]);
+                    let column_ident =
+                        self.column_expr(current_join_alias, name, current_collection)?;
                     Ok(ComparisonColumn::new_simple(
                         column_ident,
                         self.column_data_type(name, current_collection)?,
@@ -1797,72 +2021,94 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                     .name(Ident::new_quoted(arg_name.to_string())))
             };
             let table_name = ObjectName(vec![
-                Ident::new_quoted(&table.schema),
+                Ident::new_quoted(self.table_schema(&table.schema)?),
                 Ident::new_quoted(&table.name),
             ]);
-            if collection.has_arguments() {
-                let arguments = match collection {
-                    CollectionContext::Base {
-                        collection_alias: _,
-                        arguments,
-                    } => arguments
-                        .iter()
-                        .map(|(arg_name, arg)| match arg {
-                            models::Argument::Variable { name } => {
-                                variable_argument(arg_name, name)
-                            }
-                            models::Argument::Literal { value } => {
-                                literal_argument(arg_name, value)
-                            }
-                        })
-                        .collect::<Result<Vec<FunctionArg>, _>>()?,
-                    CollectionContext::Relationship {
-                        collection_alias: _,
-                        arguments,
-                        relationship_arguments,
-                    } => relationship_arguments
-                        .iter()
-                        .chain(arguments.iter())
-                        .map(|(arg_name, arg)| match arg {
-                            models::RelationshipArgument::Variable { name } => {
-                                variable_argument(arg_name, name)
-                            }
-                            models::RelationshipArgument::Literal { value } => {
-                                literal_argument(arg_name, value)
-                            }
-                            models::RelationshipArgument::Column { .. } => {
-                                Err(QueryBuilderError::NotSupported(
-                                    "Column argument value".to_string(),
-                                ))
-                            }
-                        })
-                        .collect::<Result<Vec<FunctionArg>, _>>()?,
-                    CollectionContext::UnrelatedRelationship {
-                        collection_alias: _,
-                        arguments,
-                    } => arguments
-                        .iter()
-                        .map(|(arg_name, arg)| match arg {
-                            models::RelationshipArgument::Variable { name } => {
-                                variable_argument(arg_name, name)
-                            }
-                            models::RelationshipArgument::Literal { value } => {
-                                literal_argument(arg_name, value)
-                            }
-                            models::RelationshipArgument::Column { .. } => {
-                                Err(QueryBuilderError::NotSupported(
-                                    "Column argument value".to_string(),
-                                ))
-                            }
-                        })
-                        .collect::<Result<Vec<FunctionArg>, _>>()?,
-                };
+            // sample arguments are not view parameters, they become the SAMPLE clause instead.
+            // Request arguments are not view parameters either
+            let is_view_argument = |arg_name: &ArgumentName| {
+                (table.sampling_key.is_none() || !is_sample_argument(arg_name))
+                    && !self.configuration.is_request_argument(arg_name)
+            };
+            let arguments = match collection {
+                CollectionContext::Base {
+                    collection_alias: _,
+                    arguments,
+                } => arguments
+                    .iter()
+                    .filter(|(arg_name, _)| is_view_argument(arg_name))
+                    .map(|(arg_name, arg)| match arg {
+                        models::Argument::Variable { name } => variable_argument(arg_name, name),
+                        models::Argument::Literal { value } => literal_argument(arg_name, value),
+                    })
+                    .collect::<Result<Vec<FunctionArg>, _>>()?,
+                CollectionContext::Relationship {
+                    collection_alias: _,
+                    arguments,
+                    relationship_arguments,
+                } => relationship_arguments
+                    .iter()
+                    .chain(arguments.iter())
+                    .filter(|(arg_name, _)| is_view_argument(arg_name))
+                    .map(|(arg_name, arg)| match arg {
+                        models::RelationshipArgument::Variable { name } => {
+                            variable_argument(arg_name, name)
+                        }
+                        models::RelationshipArgument::Literal { value } => {
+                            literal_argument(arg_name, value)
+                        }
+                        models::RelationshipArgument::Column { .. } => Err(
+                            QueryBuilderError::NotSupported("Column argument value".to_string()),
+                        ),
+                    })
+                    .collect::<Result<Vec<FunctionArg>, _>>()?,
+                CollectionContext::UnrelatedRelationship {
+                    collection_alias: _,
+                    arguments,
+                } => arguments
+                    .iter()
+                    .filter(|(arg_name, _)| is_view_argument(arg_name))
+                    .map(|(arg_name, arg)| match arg {
+                        models::RelationshipArgument::Variable { name } => {
+                            variable_argument(arg_name, name)
+                        }
+                        models::RelationshipArgument::Literal { value } => {
+                            literal_argument(arg_name, value)
+                        }
+                        models::RelationshipArgument::Column { .. } => Err(
+                            QueryBuilderError::NotSupported("Column argument value".to_string()),
+                        ),
+                    })
+                    .collect::<Result<Vec<FunctionArg>, _>>()?,
+            };
 
-                let table_function = table_name.into_table_function().args(arguments);
+            let sample = if arguments.is_empty() {
+                self.collection_sample(collection)?
+            } else {
+                None
+            };
+            let sampled = sample.is_some();
 
-                Ok(table_function.into_table_factor())
+            let table_factor = if arguments.is_empty() {
+                table_name
+                    .into_table_factor()
+                    .r#final(table.r#final)
+                    .sample(sample)
             } else {
-                Ok(table_name.into_table_factor())
+                let table_function = table_name.into_table_function().args(arguments);
+
+                table_function.into_table_factor()
+            };
+
+            match self.configuration.tenant_column(collection.alias()) {
+                Some(tenant_column) => self.tenant_subquery(
+                    table_factor,
+                    &table.return_type,
+                    tenant_column,
+                    sampled,
+                    parameters,
+                ),
+                None => Ok(table_factor),
             }
         } else if let Some(query) = self.configuration.queries.get(collection.alias()) {
             let get_argument = |name| match collection {
@@ -1911,6 +2157,12 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                 }),
             };
 
+            let tenant_argument = self
+                .configuration
+                .tenancy
+                .as_ref()
+                .map(|tenancy| &tenancy.argument);
+
             let elements = query
                 .query
                 .elements
@@ -1919,19 +2171,30 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                     ParameterizedQueryElement::String(s) => {
                         Ok(NativeQueryElement::String(s.to_owned()))
                     }
-                    ParameterizedQueryElement::Parameter(p) => get_argument(p.name.value())
-                        .transpose()?
-                        .ok_or_else(|| QueryBuilderError::MissingNativeQueryArgument {
-                            query: collection.alias().to_owned(),
-                            argument: p.name.value().to_owned().into(),
-                        })
-                        .and_then(|value| {
-                            Ok(NativeQueryElement::Expr(
-                                parameters.bind_json(value, p.r#type.to_owned())?,
-                            ))
-                        }),
+                    ParameterizedQueryElement::Parameter(p) => {
+                        let value = match tenant_argument
+                            .filter(|argument| argument.inner() == p.name.value())
+                        {
+                            // the tenant is always read from the request, so relationship arguments cannot override it
+                            Some(argument) => {
+                                self.request_argument(argument)?.ok_or_else(|| {
+                                    QueryBuilderError::MissingRequestArgument(argument.to_owned())
+                                })?
+                            }
+                            None => get_argument(p.name.value()).transpose()?.ok_or_else(|| {
+                                QueryBuilderError::MissingNativeQueryArgument {
+                                    query: collection.alias().to_owned(),
+                                    argument: p.name.value().to_owned().into(),
+                                }
+                            })?,
+                        };
+
+                        Ok(NativeQueryElement::Expr(
+                            parameters.bind_json(value, p.r#type.to_owned())?,
+                        ))
+                    }
                 })
-                .collect::<Result<_, _>>()?;
+                .collect::<Result<_, QueryBuilderError>>()?;
 
             Ok(NativeQuery::new(elements).into_table_factor())
         } else {
@@ -1940,13 +2203,120 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
             ))
         }
     }
+    /// The schema to read a table from. Tables in a routed database are read from the database supplied by the request
+    fn table_schema<'s>(&'s self, schema: &'s str) -> Result<&'s str, QueryBuilderError> {
+        let database_routing = match &self.configuration.database_routing {
+            Some(database_routing)
+                if database_routing
+                    .databases
+                    .iter()
+                    .any(|database| database == schema) =>
+            {
+                database_routing
+            }
+            _ => return Ok(schema),
+        };
+
+        let database = self
+            .request_argument(&database_routing.argument)?
+            .ok_or_else(|| {
+                QueryBuilderError::MissingRequestArgument(database_routing.argument.to_owned())
+            })?;
+
+        match database {
+            serde_json::Value::String(database) => database_routing
+                .databases
+                .iter()
+                .find(|allowed| *allowed == database)
+                .map(String::as_str)
+                .ok_or_else(|| QueryBuilderError::DatabaseNotAllowed(database.to_owned())),
+            _ => Err(QueryBuilderError::InvalidRequestArgument {
+                argument: database_routing.argument.to_owned(),
+                value: database.to_owned(),
+            }),
+        }
+    }
+    /// Restrict a table to the rows of the tenant supplied by the request.
+    /// The table is wrapped in a subquery, so the restriction applies wherever the table is referenced,
+    /// and cannot be bypassed by predicates in the request
+    fn tenant_subquery(
+        &self,
+        table_factor: TableFactor,
+        return_type: &ObjectTypeName,
+        tenant_column: &FieldName,
+        sampled: bool,
+        parameters: &mut ParameterBuilder,
+    ) -> Result<TableFactor, QueryBuilderError> {
+        let tenancy = self.configuration.tenancy.as_ref().ok_or_else(|| {
+            QueryBuilderError::Unexpected("tenant column without tenancy configuration".to_string())
+        })?;
+
+        let tenant = self.request_argument(&tenancy.argument)?.ok_or_else(|| {
+            QueryBuilderError::MissingRequestArgument(tenancy.argument.to_owned())
+        })?;
+
+        let table_type = self
+            .configuration
+            .table_types
+            .get(return_type)
+            .ok_or_else(|| QueryBuilderError::UnknownTableType(return_type.to_owned()))?;
+
+        // select the columns explicitly: ALIAS and MATERIALIZED columns are not included in *
+        let mut select: Vec<SelectItem> = table_type
+            .columns
+            .iter()
+            .flat_map(|(column_alias, data_type)| match data_type {
+                ClickHouseDataType::Nested(elements) => elements
+                    .iter()
+                    .map(|(name, _)| Ident::new_quoted(format!("{column_alias}.{}", name.value())))
+                    .collect(),
+                _ => vec![self.column_ident(column_alias)],
+            })
+            .map(|ident| ident.into_expr().into_select(None::<Ident>))
+            .collect();
+
+        if sampled {
+            // the virtual _sample_factor column is used to scale aggregates
+            select.push(
+                Ident::new_quoted("_sample_factor")
+                    .into_expr()
+                    .into_select(Some("_sample_factor")),
+            );
+        }
+
+        let predicate = Expr::BinaryOp {
+            left: self.column_ident(tenant_column).into_expr().into_box(),
+            op: BinaryOperator::Eq,
+            right: parameters
+                .bind_json(tenant, tenancy.data_type.to_owned().into())?
+                .into_box(),
+        };
+
+        Ok(Query::new()
+            .select(select)
+            .from(vec![table_factor.into_table_with_joins(vec![])])
+            .predicate(Some(predicate))
+            .into_table_factor())
+    }
     fn column_ident(&self, column_alias: &FieldName) -> Ident {
         Ident::new_quoted(column_alias.to_string())
     }
+    /// the data type of values read from this column.
+    /// For AggregateFunction columns, this is the finalized type rather than the stored state
     fn column_data_type(
         &self,
         column_alias: &FieldName,
         collection: &CollectionContext,
+    ) -> Result<ClickHouseDataType, QueryBuilderError> {
+        let column_type = self.column_storage_type(column_alias, collection)?;
+
+        Ok(finalized_aggregate_type(&column_type).unwrap_or(column_type))
+    }
+    /// the data type of the column, as stored in the database
+    fn column_storage_type(
+        &self,
+        column_alias: &FieldName,
+        collection: &CollectionContext,
     ) -> Result<ClickHouseDataType, QueryBuilderError> {
         let return_type = self
             .configuration
@@ -1973,9 +2343,175 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
 
         Ok(column_type.to_owned())
     }
+    /// AggregateFunction columns store intermediate aggregation states.
+    /// Returns the name of the function that produced the state, if the column holds states we know how to finalize
+    fn column_state_function(
+        &self,
+        column_alias: &FieldName,
+        collection: &CollectionContext,
+    ) -> Result<Option<String>, QueryBuilderError> {
+        let column_type = self.column_storage_type(column_alias, collection)?;
+
+        Ok(match &column_type {
+            ClickHouseDataType::AggregateFunction { function, .. }
+                if finalized_aggregate_type(&column_type).is_some() =>
+            {
+                Some(function.name.value().to_owned())
+            }
+            _ => None,
+        })
+    }
+    /// An expression reading the column value. AggregateFunction states are finalized,
+    /// and Nested columns are reconstructed from their subcolumns
+    fn column_expr(
+        &self,
+        table_alias: &Ident,
+        column_alias: &FieldName,
+        collection: &CollectionContext,
+    ) -> Result<Expr, QueryBuilderError> {
+        if let ClickHouseDataType::Nested(elements) =
+            self.column_storage_type(column_alias, collection)?
+        {
+            return Ok(nested_column_expr(table_alias, column_alias, &elements));
+        }
+
+        let expr =
+            Expr::CompoundIdentifier(vec![table_alias.clone(), self.column_ident(column_alias)]);
+
+        if self
+            .column_state_function(column_alias, collection)?
+            .is_some()
+        {
+            Ok(Function::new_unquoted("finalizeAggregation")
+                .args(vec![expr.into_arg()])
+                .into_expr())
+        } else {
+            Ok(expr)
+        }
+    }
+    /// The SAMPLE clause for this collection, if the table has a sampling key and a sample ratio was supplied
+    fn collection_sample(
+        &self,
+        collection: &CollectionContext,
+    ) -> Result<Option<Sample>, QueryBuilderError> {
+        match self.configuration.tables.get(collection.alias()) {
+            Some(table) if table.sampling_key.is_some() => {}
+            _ => return Ok(None),
+        }
+
+        let ratio = sample_argument(collection, SAMPLE_RATIO_ARGUMENT, |ratio| {
+            ratio > 0.0 && ratio <= 1.0
+        })?;
+        let offset = sample_argument(collection, SAMPLE_OFFSET_ARGUMENT, |offset| {
+            (0.0..1.0).contains(&offset)
+        })?;
+
+        match (ratio, offset) {
+            (Some(ratio), offset) => Ok(Some(Sample { ratio, offset })),
+            (None, None) => Ok(None),
+            (None, Some(_)) => Err(QueryBuilderError::MissingSampleRatio(
+                collection.alias().to_owned(),
+            )),
+        }
+    }
+    /// Aggregates over AggregateFunction columns merge the stored states when the states can produce the requested aggregate:
+    /// when the requested function is the function that produced the states, or when summing count states.
+    /// Returns the state function to merge, or None if the aggregate is applied to the column values.
+    /// The finalized values are only complete once all rows with the same key have been merged,
+    /// so other aggregates over AggregateFunction columns, including counts, are only supported for tables read with FINAL
+    fn aggregate_state_merge(
+        &self,
+        column_alias: &FieldName,
+        function: Option<&ClickHouseSingleColumnAggregateFunction>,
+        collection: &CollectionContext,
+    ) -> Result<Option<String>, QueryBuilderError> {
+        let state_function = match self.column_state_function(column_alias, collection)? {
+            Some(state_function) => state_function,
+            None => return Ok(None),
+        };
+
+        // -If states are merged the same way as the states of the underlying function
+        let base_function = state_function.strip_suffix("If").unwrap_or(&state_function);
+
+        let mergeable = function.is_some_and(|function| {
+            base_function == function_name(function)
+                || (base_function == "count"
+                    && *function == ClickHouseSingleColumnAggregateFunction::Sum)
+        });
+
+        if mergeable {
+            return Ok(Some(state_function));
+        }
+
+        let is_final = self
+            .configuration
+            .tables
+            .get(collection.alias())
+            .is_some_and(|table| table.r#final);
+
+        if is_final {
+            Ok(None)
+        } else {
+            Err(QueryBuilderError::UnmergeableAggregateState {
+                column: column_alias.to_owned(),
+                function: function.map_or("count", function_name).to_owned(),
+                state_function,
+            })
+        }
+    }
+    /// Counts and sums grow with the number of rows read, so they must be scaled when sampling.
+    /// Other aggregates, including distinct counts, are estimated directly from the sample.
+    /// Sums of decimals are not scaled, as multiplying by the floating point sample factor would change the result type
+    fn sample_scaling(
+        &self,
+        aggregate: &models::Aggregate,
+        collection: &CollectionContext,
+    ) -> Result<SampleScaling, QueryBuilderError> {
+        use ClickHouseDataType as DT;
+
+        Ok(match aggregate {
+            models::Aggregate::StarCount {} => SampleScaling::MultiplyAndRound,
+            models::Aggregate::ColumnCount { distinct: true, .. } => SampleScaling::None,
+            models::Aggregate::ColumnCount {
+                distinct: false, ..
+            } => SampleScaling::MultiplyAndRound,
+            models::Aggregate::SingleColumn {
+                function, column, ..
+            } => {
+                if !matches!(
+                    aggregate_function(function)?,
+                    ClickHouseSingleColumnAggregateFunction::Sum
+                ) {
+                    return Ok(SampleScaling::None);
+                }
+
+                let mut data_type = self.column_data_type(column, collection)?;
+                while let DT::Nullable(inner) | DT::LowCardinality(inner) = data_type {
+                    data_type = *inner;
+                }
+
+                match data_type {
+                    DT::UInt8
+                    | DT::UInt16
+                    | DT::UInt32
+                    | DT::UInt64
+                    | DT::UInt128
+                    | DT::UInt256
+                    | DT::Int8
+                    | DT::Int16
+                    | DT::Int32
+                    | DT::Int64
+                    | DT::Int128
+                    | DT::Int256 => SampleScaling::MultiplyAndRound,
+                    DT::Float32 | DT::Float64 => SampleScaling::Multiply,
+                    _ => SampleScaling::None,
+                }
+            }
+        })
+    }
     fn column_accessor(
         &self,
-        column_ident: Vec<Ident>,
+        column: Expr,
         type_definition: &ClickHouseTypeDefinition,
         traversed_array: bool,
         field_selector: Option<&models::NestedField>,
@@ -1998,7 +2534,7 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                     let ident = Ident::new_unquoted("_value");
 
                     if let Some((expr, joins)) = self.column_accessor(
-                        vec![ident.clone()],
+                        Expr::CompoundIdentifier(vec![ident.clone()]),
                         element_type,
                         true,
                         Some(&inner.fields),
@@ -2012,7 +2548,7 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                         let expr = Function::new_unquoted("arrayMap")
                             .args(vec![
                                 Lambda::new(vec![ident], expr).into_expr().into_arg(),
-                                Expr::CompoundIdentifier(column_ident).into_arg(),
+                                column.into_arg(),
                             ])
                             .into_expr();
 
@@ -2032,12 +2568,23 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                         }
                     };
 
-                    let chain_ident = |i: &FieldName| -> Vec<Ident> {
-                        column_ident
-                            .clone()
-                            .into_iter()
-                            .chain(iter::once(Ident::new_quoted(i.to_string())))
-                            .collect()
+                    let chain_field = |i: &FieldName| -> Expr {
+                        match &column {
+                            Expr::CompoundIdentifier(column_ident) => Expr::CompoundIdentifier(
+                                column_ident
+                                    .clone()
+                                    .into_iter()
+                                    .chain(iter::once(Ident::new_quoted(i.to_string())))
+                                    .collect(),
+                            ),
+                            _ => Function::new_unquoted("tupleElement")
+                                .args(vec![
+                                    column.clone().into_arg(),
+                                    Expr::Value(Value::SingleQuotedString(i.to_string()))
+                                        .into_arg(),
+                                ])
+                                .into_expr(),
+                        }
                     };
 
                     let mut required_joins = vec![];
@@ -2061,9 +2608,9 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                                         }
                                     })?;
 
-                                let column_ident = chain_ident(column);
+                                let field_expr = chain_field(column);
                                 if let Some((expr, mut joins)) = self.column_accessor(
-                                    column_ident.clone(),
+                                    field_expr.clone(),
                                     type_definition,
                                     traversed_array,
                                     fields.as_ref(),
@@ -2074,7 +2621,7 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                                     required_joins.append(&mut joins);
                                     column_accessors.push(expr);
                                 } else {
-                                    column_accessors.push(Expr::CompoundIdentifier(column_ident));
+                                    column_accessors.push(field_expr);
                                 }
                             }
                             models::Field::Relationship {
@@ -2088,10 +2635,19 @@ impl<'r, 'c> QueryBuilder<'r, 'c> {
                                     ));
                                 }
 
+                                let column_ident =
+                                    match &column {
+                                        Expr::CompoundIdentifier(column_ident) => column_ident,
+                                        _ => return Err(QueryBuilderError::NotSupported(
+                                            "Relationships with fields nested in computed columns"
+                                                .to_string(),
+                                        )),
+                                    };
+
                                 let (expr, join) = self.field_relationship(
                                     alias,
                                     rel_index,
-                                    &column_ident,
+                                    column_ident,
                                     query,
                                     relationship,
                                     arguments,
@@ -2147,6 +2703,169 @@ fn get_return_type<'a>(
         .ok_or_else(|| QueryBuilderError::UnknownTable(table_alias.to_owned()))
 }
 
+/// Nested columns are stored as parallel `column.field` arrays, which may not be readable as a single column.
+/// We zip the subcolumns into an array of tuples, then cast to name the tuple elements
+fn nested_column_expr(
+    table_alias: &Ident,
+    column_alias: &FieldName,
+    elements: &[(Identifier, ClickHouseDataType)],
+) -> Expr {
+    let subcolumns = elements
+        .iter()
+        .map(|(name, _)| {
+            Expr::CompoundIdentifier(vec![
+                table_alias.clone(),
+                Ident::new_quoted(format!("{column_alias}.{}", name.value())),
+            ])
+            .into_arg()
+        })
+        .collect();
+
+    let cast_type = ClickHouseDataType::Array(Box::new(ClickHouseDataType::Tuple(
+        elements
+            .iter()
+            .map(|(name, data_type)| (Some(name.to_owned()), data_type.to_owned()))
+            .collect(),
+    )));
+
+    Function::new_unquoted("cast")
+        .args(vec![
+            Function::new_unquoted("arrayZip")
+                .args(subcolumns)
+                .into_expr()
+                .into_arg(),
+            Expr::Value(Value::SingleQuotedString(cast_type.to_string())).into_arg(),
+        ])
+        .into_expr()
+}
+
+/// The top level conjuncts of a predicate, flattening nested AND expressions
+fn conjuncts(expression: &models::Expression) -> Vec<&models::Expression> {
+    match expression {
+        models::Expression::And { expressions } => expressions.iter().flat_map(conjuncts).collect(),
+        _ => vec![expression],
+    }
+}
+
+/// Comparisons of a column on the current table to a literal value.
+/// PREWHERE can only reference the table in the FROM clause: columns reached through a relationship path are read from joined tables,
+/// and root collection columns reference the outer query when filtering relationships, so neither are candidates
+fn is_prewhere_candidate(expression: &models::Expression, prewhere_columns: &[&FieldName]) -> bool {
+    let column = match expression {
+        models::Expression::BinaryComparisonOperator {
+            column,
+            operator: _,
+            value: models::ComparisonValue::Scalar { .. },
+        }
+        | models::Expression::UnaryComparisonOperator {
+            column,
+            operator: _,
+        } => column,
+        _ => return false,
+    };
+
+    match column {
+        models::ComparisonTarget::Column {
+            name,
+            path,
+            field_path: _,
+        } => path.is_empty() && prewhere_columns.contains(&name),
+        models::ComparisonTarget::RootCollectionColumn { .. } => false,
+    }
+}
+
+/// How an aggregate is adjusted to approximate the result over the full table when sampling
+enum SampleScaling {
+    /// The aggregate is estimated directly from the sample
+    None,
+    /// The aggregate is multiplied by the sample factor
+    Multiply,
+    /// The aggregate is multiplied by the sample factor, and rounded so it can be cast back to an integer type
+    MultiplyAndRound,
+}
+
+fn apply_sample_scaling(expr: Expr, sample_factor: &Expr, scaling: SampleScaling) -> Expr {
+    let multiply = |expr: Expr| {
+        Function::new_unquoted("multiply")
+            .args(vec![expr.into_arg(), sample_factor.to_owned().into_arg()])
+            .into_expr()
+    };
+
+    match scaling {
+        SampleScaling::None => expr,
+        SampleScaling::Multiply => multiply(expr),
+        SampleScaling::MultiplyAndRound => Function::new_unquoted("round")
+            .args(vec![multiply(expr).into_arg()])
+            .into_expr(),
+    }
+}
+
+fn is_sample_argument(name: &ArgumentName) -> bool {
+    name.inner() == SAMPLE_RATIO_ARGUMENT || name.inner() == SAMPLE_OFFSET_ARGUMENT
+}
+
+/// Sample arguments must be literal numbers: ClickHouse does not accept query parameters in the SAMPLE clause.
+/// Null values are treated as if the argument was not supplied
+fn sample_argument(
+    collection: &CollectionContext,
+    name: &str,
+    is_valid: impl Fn(f64) -> bool,
+) -> Result<Option<Value>, QueryBuilderError> {
+    let argument_name: ArgumentName = name.to_owned().into();
+
+    let value = match collection {
+        CollectionContext::Base {
+            collection_alias: _,
+            arguments,
+        } => arguments.get(&argument_name).map(|arg| match arg {
+            models::Argument::Literal { value } => Ok(value),
+            models::Argument::Variable { .. } => Err(QueryBuilderError::NotSupported(
+                "sample argument from variable".to_string(),
+            )),
+        }),
+        CollectionContext::Relationship {
+            collection_alias: _,
+            arguments,
+            relationship_arguments,
+        } => arguments
+            .get(&argument_name)
+            .or_else(|| relationship_arguments.get(&argument_name))
+            .map(relationship_literal_argument),
+        CollectionContext::UnrelatedRelationship {
+            collection_alias: _,
+            arguments,
+        } => arguments
+            .get(&argument_name)
+            .map(relationship_literal_argument),
+    }
+    .transpose()?;
+
+    match value {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::Number(number)) if number.as_f64().is_some_and(&is_valid) => {
+            Ok(Some(Value::Number(number.to_string())))
+        }
+        Some(value) => Err(QueryBuilderError::InvalidSampleArgument {
+            argument: argument_name,
+            value: value.to_owned(),
+        }),
+    }
+}
+
+fn relationship_literal_argument(
+    argument: &models::RelationshipArgument,
+) -> Result<&serde_json::Value, QueryBuilderError> {
+    match argument {
+        models::RelationshipArgument::Literal { value } => Ok(value),
+        models::RelationshipArgument::Variable { .. } => Err(QueryBuilderError::NotSupported(
+            "sample argument from variable".to_string(),
+        )),
+        models::RelationshipArgument::Column { .. } => Err(QueryBuilderError::NotSupported(
+            "sample argument from column".to_string(),
+        )),
+    }
+}
+
 fn aggregate_function(
     name: &AggregateFunctionName,
 ) -> Result<ClickHouseSingleColumnAggregateFunction, QueryBuilderError> {
@@ -61,21 +61,4 @@ impl<'a, 'b> CollectionContext<'a, 'b> {
             } => collection_alias,
         }
     }
-    pub fn has_arguments(&self) -> bool {
-        match self {
-            CollectionContext::Base {
-                collection_alias: _,
-                arguments,
-            } => !arguments.is_empty(),
-            CollectionContext::Relationship {
-                collection_alias: _,
-                arguments,
-                relationship_arguments,
-            } => !arguments.is_empty() || !relationship_arguments.is_empty(),
-            CollectionContext::UnrelatedRelationship {
-                collection_alias: _,
-                arguments,
-            } => !arguments.is_empty(),
-        }
-    }
 }
@@ -97,6 +97,41 @@ pub enum QueryBuilderError {
         data_type: ParameterType,
         field: String,
     },
+    /// A sample argument was not a literal number in the accepted range
+    #[error("Invalid value `{value}` for sample argument {argument}")]
+    InvalidSampleArgument {
+        argument: ArgumentName,
+        value: serde_json::Value,
+    },
+    /// A sample offset was supplied without a sample ratio
+    #[error("Argument _sample_offset for table {0} requires _sample_ratio")]
+    MissingSampleRatio(CollectionName),
+    /// An argument required for every request was not supplied
+    #[error("Argument {0} is required")]
+    MissingRequestArgument(ArgumentName),
+    /// A request argument had an invalid value
+    #[error("Invalid value `{value}` for argument {argument}")]
+    InvalidRequestArgument {
+        argument: ArgumentName,
+        value: serde_json::Value,
+    },
+    /// A request was routed to a database that is not in the allowed list
+    #[error("Database {0} is not allowed")]
+    DatabaseNotAllowed(String),
+    /// An aggregate over an AggregateFunction column cannot be computed by merging the stored states,
+    /// and the finalized values may be partial, as the table is not read with FINAL
+    #[error("Cannot compute {function} over column {column}, which stores {state_function} states. Set final for the table to aggregate the finalized values")]
+    UnmergeableAggregateState {
+        column: FieldName,
+        function: String,
+        state_function: String,
+    },
+    /// A string value could not be parsed as a date or timestamp
+    #[error("Invalid date or timestamp `{value}` for type `{data_type}`")]
+    InvalidDateTime {
+        value: String,
+        data_type: ParameterType,
+    },
 }
 
 impl From<QueryBuilderError> for ErrorResponse {
@@ -119,11 +154,22 @@ impl From<QueryBuilderError> for ErrorResponse {
             | QueryBuilderError::ExpectedAnonymousTuple { .. }
             | QueryBuilderError::ExpectedNamedTuple { .. }
             | QueryBuilderError::MissingNamedField { .. }
+            | QueryBuilderError::InvalidDateTime { .. }
+            | QueryBuilderError::InvalidSampleArgument { .. }
+            | QueryBuilderError::MissingSampleRatio(_)
+            | QueryBuilderError::InvalidRequestArgument { .. }
+            | QueryBuilderError::UnmergeableAggregateState { .. }
             | QueryBuilderError::TupleLengthMismatch { .. } => ErrorResponse::new(
                 StatusCode::BAD_REQUEST,
                 value.to_string(),
                 serde_json::Value::Null,
             ),
+            QueryBuilderError::MissingRequestArgument(_)
+            | QueryBuilderError::DatabaseNotAllowed(_) => ErrorResponse::new(
+                StatusCode::FORBIDDEN,
+                value.to_string(),
+                serde_json::Value::Null,
+            ),
             QueryBuilderError::NotSupported(_) => ErrorResponse::new(
                 StatusCode::NOT_IMPLEMENTED,
                 value.to_string(),
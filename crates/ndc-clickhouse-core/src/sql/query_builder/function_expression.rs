@@ -2,20 +2,29 @@ use crate::sql::ast::{Expr, Function};
 use common::schema::single_column_aggregate_function::ClickHouseSingleColumnAggregateFunction;
 
 pub fn apply_function(function: &ClickHouseSingleColumnAggregateFunction, column: Expr) -> Expr {
+    Function::new_unquoted(function_name(function))
+        .args(vec![column.into_arg()])
+        .into_expr()
+}
+
+/// Merge aggregate function states using the -Merge combinator
+/// The state function must be the function that produced the states
+pub fn apply_merge_function(state_function: &str, column: Expr) -> Expr {
+    Function::new_unquoted(format!("{state_function}Merge"))
+        .args(vec![column.into_arg()])
+        .into_expr()
+}
+
+pub fn function_name(function: &ClickHouseSingleColumnAggregateFunction) -> &'static str {
     use ClickHouseSingleColumnAggregateFunction::*;
-    let sql_fn = |name: &str, arg: Expr| {
-        Function::new_unquoted(name)
-            .args(vec![arg.into_arg()])
-            .into_expr()
-    };
     match function {
-        Max => sql_fn("max", column),
-        Min => sql_fn("min", column),
-        Sum => sql_fn("sum", column),
-        Avg => sql_fn("avg", column),
-        StddevPop => sql_fn("stddevPop", column),
-        StddevSamp => sql_fn("stddevSamp", column),
-        VarPop => sql_fn("varPop", column),
-        VarSamp => sql_fn("varSamp", column),
+        Max => "max",
+        Min => "min",
+        Sum => "sum",
+        Avg => "avg",
+        StddevPop => "stddevPop",
+        StddevSamp => "stddevSamp",
+        VarPop => "varPop",
+        VarSamp => "varSamp",
     }
 }
@@ -210,6 +210,22 @@ mod tests {
                 "(['bar'], NULL)",
             ),
             (json!(["foo", 1]), "Tuple(String, Int32)", "('foo', 1)"),
+            (json!("2024-01-31"), "Date", "'2024-01-31'"),
+            (
+                json!("2024-01-31T12:30:00Z"),
+                "DateTime",
+                "'2024-01-31T12:30:00Z'",
+            ),
+            (
+                json!("2024-01-31T12:30:00.123+02:00"),
+                "Nullable(DateTime64(3, 'Europe/Berlin'))",
+                "'2024-01-31T12:30:00.123+02:00'",
+            ),
+            (
+                json!("2024-01-31 12:30:00"),
+                "DateTime",
+                "'2024-01-31 12:30:00'",
+            ),
         ];
 
         for (value, data_type_string, expected) in &test_cases {
@@ -237,6 +253,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn reject_invalid_date_time_parameters() {
+        let mut parameters = ParameterBuilder::new(false);
+
+        let test_cases = vec![
+            (json!("2024-13-01"), "Date"),
+            (json!("31/01/2024"), "Date32"),
+            (json!("2024-01-31T25:00:00Z"), "DateTime"),
+            (json!("yesterday"), "Nullable(DateTime64(3))"),
+            (json!(["2024-01-31", "foo"]), "Array(Date)"),
+        ];
+
+        for (value, data_type_string) in &test_cases {
+            let data_type = ClickHouseDataType::from_str(data_type_string)
+                .expect("Data type string should be valid ClickHouseDataType");
+            let result = parameters.bind_json(value, data_type.into());
+
+            assert!(
+                matches!(result, Err(QueryBuilderError::InvalidDateTime { .. })),
+                "parameter {} of type {} should be rejected",
+                value,
+                data_type_string,
+            )
+        }
+    }
+
     #[test]
     fn bind_string_parameters() {
         let mut parameters = ParameterBuilder::new(false);
@@ -113,6 +113,7 @@ impl AggregatesTypeString {
                             column_alias,
                             return_type,
                             &config.namespace_separator,
+                            &config.scalar_types,
                         );
 
                         let aggregate_function =
@@ -186,6 +187,7 @@ impl RowTypeString {
                                     column_alias,
                                     return_type,
                                     &config.namespace_separator,
+                                    &config.scalar_types,
                                 );
 
                                 FieldTypeString::new(
@@ -1,5 +1,5 @@
 use common::{
-    config::{read_server_config, ConfigurationEnvironment, ServerConfig},
+    config::{read_server_config, ConfigurationEnvironment, ConfigurationError, ServerConfig},
     config_file::ServerConfigFile,
     schema::schema_response,
 };
@@ -15,20 +15,24 @@ fn base_path() -> PathBuf {
         .join("query_builder")
 }
 
-async fn read_mock_configuration(schema_dir: &str) -> ServerConfig {
+fn mock_environment() -> ConfigurationEnvironment {
     // set mock values for required env vars, we won't be reading these anyways
     let env = HashMap::from_iter(vec![
-        ("CLICKHOUSE_URL".to_owned(), "".to_owned()),
+        (
+            "CLICKHOUSE_URL".to_owned(),
+            "http://localhost:8123".to_owned(),
+        ),
         ("CLICKHOUSE_USERNAME".to_owned(), "".to_owned()),
         ("CLICKHOUSE_PASSWORD".to_owned(), "".to_owned()),
     ]);
+    ConfigurationEnvironment::from_simulated_environment(env)
+}
+
+async fn read_mock_configuration(schema_dir: &str) -> ServerConfig {
     let config_dir = base_path().join(schema_dir).join("_config");
-    read_server_config(
-        config_dir.as_path(),
-        &ConfigurationEnvironment::from_simulated_environment(env),
-    )
-    .await
-    .expect("Should be able to read configuration")
+    read_server_config(config_dir.as_path(), &mock_environment())
+        .await
+        .expect("Should be able to read configuration")
 }
 
 fn pretty_print_sql(query: &str) -> String {
@@ -45,7 +49,15 @@ fn pretty_print_sql(query: &str) -> String {
 
 #[tokio::test]
 async fn test_sql_generation() {
-    for schema_dir in ["chinook", "complex_columns", "star_schema"] {
+    for schema_dir in [
+        "chinook",
+        "complex_columns",
+        "database_routing",
+        "role_forwarding",
+        "scalar_types",
+        "star_schema",
+        "tenancy",
+    ] {
         let configuration = read_mock_configuration(schema_dir).await;
 
         glob!(
@@ -56,20 +68,22 @@ async fn test_sql_generation() {
                 let request: models::QueryRequest =
                     serde_json::from_str(&file).expect("File should be valid query request");
 
-                let inlined_sql = match QueryBuilder::new(&request, &configuration).build_inlined()
+                let builder = QueryBuilder::new(&request, &configuration);
+                // some errors, such as invalid request arguments, only surface when building the url parameters
+                let (inlined_sql, (parameterized_sql, parameters)) = match builder
+                    .build_inlined()
+                    .and_then(|inlined_sql| Ok((inlined_sql, builder.build_parameterized()?)))
                 {
                     Err(err) => {
                         assert_snapshot!(format!("{schema_dir} Expected Error"), err);
                         return;
                     }
-                    Ok(inlined_sql) => pretty_print_sql(&inlined_sql.to_string()),
+                    Ok(result) => result,
                 };
 
+                let inlined_sql = pretty_print_sql(&inlined_sql.to_string());
                 assert_snapshot!(format!("{schema_dir} Inlined SQL"), inlined_sql);
 
-                let (parameterized_sql, parameters) = QueryBuilder::new(&request, &configuration)
-                    .build_parameterized()
-                    .expect("Should build parameterized SQL");
                 let parameterized_sql = pretty_print_sql(&parameterized_sql.to_string());
 
                 if parameters.is_empty() {
@@ -100,7 +114,15 @@ async fn test_sql_generation() {
 
 #[tokio::test]
 async fn test_schemas() {
-    for schema_dir in ["chinook", "complex_columns", "star_schema"] {
+    for schema_dir in [
+        "chinook",
+        "complex_columns",
+        "database_routing",
+        "role_forwarding",
+        "scalar_types",
+        "star_schema",
+        "tenancy",
+    ] {
         let configuration = read_mock_configuration(schema_dir).await;
 
         let schema = schema_response(&configuration);
@@ -108,6 +130,35 @@ async fn test_schemas() {
     }
 }
 
+#[tokio::test]
+async fn test_configuration_errors() {
+    for case in [
+        "01_table_alias_collides_with_scalar_type",
+        "02_table_alias_collides_with_nested_type",
+        "03_query_alias_collides_with_table_alias",
+        "04_reserved_setting",
+        "05_invalid_setting_name",
+        "06_native_query_without_tenant",
+        "07_native_query_tenant_type_mismatch",
+    ] {
+        let config_dir = base_path().join("configuration_errors").join(case);
+        let err = read_server_config(config_dir.as_path(), &mock_environment())
+            .await
+            .expect_err("Configuration should be invalid");
+
+        match err {
+            // the file path is machine specific, so is left out of the snapshot
+            ConfigurationError::ValidateError {
+                node_path, message, ..
+            } => assert_snapshot!(
+                format!("{case} Configuration Error"),
+                format!("{}: {message}", node_path.join("."))
+            ),
+            err => panic!("Expected a validation error, got {err}"),
+        }
+    }
+}
+
 #[tokio::test]
 #[ignore]
 async fn update_json_schema() -> Result<(), Box<dyn Error>> {
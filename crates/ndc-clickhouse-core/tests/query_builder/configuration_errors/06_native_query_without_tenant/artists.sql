@@ -0,0 +1 @@
+SELECT * FROM "default"."artists"
@@ -0,0 +1 @@
+SELECT * FROM "default"."artists" WHERE TenantId = {_tenant: UInt32}
@@ -0,0 +1,3 @@
+SELECT ArtistId, Name, TenantId
+FROM "Chinook"."Artist"
+WHERE TenantId = {_tenant: String} AND Name = {name: String}